@@ -0,0 +1,193 @@
+//! Times parsing and gathering generated logs that are unusually wide or long,
+//! counting the allocations made along the way
+//!
+//! Run with `cargo bench`. The logs are made up, so there's no repo to clone,
+//! and they're laid out just like the `git log -z` output `parse_log()` expects.
+
+extern crate git_historian;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use git_historian::{PathSet, path_from_bytes};
+use git_historian::history::{gather_history_with, GatherOptions, NodeContext};
+use git_historian::parsing::{parse_log, DeltaPool, LogOptions};
+
+/// Counts every allocation (and reallocation) so we can see churn,
+/// not just time
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// How many times we run each case, keeping the fastest
+const RUNS: usize = 5;
+
+/// Builds a log of `commits` commits (newest first, each the parent of the
+/// one before it), where `deltas(n)` gives the status and path tokens of commit `n`
+fn generate<D>(commits: usize, deltas: D) -> Vec<u8>
+    where D: Fn(usize) -> Vec<String> {
+    let mut log = Vec::new();
+    for n in (1..=commits).rev() {
+        if !log.is_empty() {
+            log.push(0);
+        }
+        let parents = if n > 1 { format!("{:040x}", n - 1) } else { String::new() };
+        log.extend(format!("{:040x}\0{} +0000\0{}\0", n, 1_500_000_000 + n, parents).bytes());
+        for (i, token) in deltas(n).iter().enumerate() {
+            if i == 0 {
+                log.push(b'\n');
+            }
+            log.extend(token.bytes());
+            log.push(0);
+        }
+    }
+    log
+}
+
+/// Runs `f` a few times, printing the fastest run and how much it allocated,
+/// and returns the allocations of the last run
+fn bench<F: FnMut()>(name: &str, mut f: F) -> usize {
+    let mut best = Duration::MAX;
+    let mut allocations = 0;
+    for _ in 0..RUNS {
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        f();
+        best = best.min(start.elapsed());
+        allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    }
+    println!("{:<40} {:>10.1?} {:>12} allocations", name, best, allocations);
+    allocations
+}
+
+/// Parses `log` with `options`, throwing the commits away on another thread
+fn parse(log: &[u8], options: &LogOptions) -> usize {
+    let (tx, rx) = sync_channel(64);
+    let consumer = thread::spawn(move || rx.iter().count());
+    parse_log(log, options, &tx).unwrap();
+    drop(tx);
+    consumer.join().unwrap()
+}
+
+/// A few bulk-reformat commits, each touching tens of thousands of files
+fn wide_commits() {
+    let log = generate(6, |_| {
+        (0..30_000).flat_map(|f| vec!["M".to_string(), format!("src/dir{}/file{}.rs", f % 100, f)])
+            .collect()
+    });
+    let allocations = bench("wide: 6 commits of 30,000 deltas", || {
+        assert_eq!(parse(&log, &LogOptions::new()), 6);
+    });
+    // The parser reuses its token buffers, so each delta should only
+    // allocate its path (plus a bit of slack for the commits themselves).
+    assert!(allocations < 6 * 30_000 + 1_000,
+            "Parsing allocated {} times for 180,000 deltas", allocations);
+}
+
+/// A long history of small commits, gathered with and without a `DeltaPool`
+fn many_commits() {
+    const FILES: usize = 50;
+    let name = |f: usize| format!("src/file{}.rs", f);
+    let log = generate(20_000, |n| {
+        if n == 1 {
+            return (0..FILES).flat_map(|f| vec!["A".to_string(), name(f)]).collect();
+        }
+        (0..5).flat_map(|i| vec!["M".to_string(), name((n * 5 + i) % FILES)]).collect()
+    });
+    let paths: PathSet = (0..FILES).map(|f| path_from_bytes(name(f).into_bytes())).collect();
+
+    for &pooled in &[false, true] {
+        let label = if pooled { "long: 20,000 commits, pooled" }
+                    else { "long: 20,000 commits" };
+        bench(label, || {
+            let pool = if pooled { Some(DeltaPool::new()) } else { None };
+            let mut log_options = LogOptions::new();
+            log_options.delta_pool(pool.clone());
+            let mut options = GatherOptions::new();
+            options.recycle_deltas(pool);
+
+            let (tx, rx) = sync_channel(64);
+            let tree = thread::scope(|s| {
+                s.spawn(|| {
+                    parse_log(&log[..], &log_options, &tx).unwrap();
+                    drop(tx);
+                });
+                gather_history_with(&paths, |_: &NodeContext| (), |_: &NodeContext| true,
+                                    options, &rx).unwrap()
+            });
+            assert_eq!(tree.len(), FILES);
+        });
+    }
+}
+
+/// Lots of distinct paths, with a file renamed in every commit,
+/// which is what the history builder's path interning is for
+fn many_paths() {
+    const FILES: usize = 5_000;
+    const COMMITS: usize = 10_000;
+    let name = |f: usize, version: usize| format!("src/dir{}/file{}.v{}.rs", f % 40, f, version);
+
+    // Work out each commit's deltas oldest first, renaming as we go.
+    let mut versions = vec![0; FILES];
+    let mut commits = vec![Vec::new(), (0..FILES).flat_map(|f| vec!["A".to_string(), name(f, 0)])
+        .collect()];
+    for n in 2..=COMMITS {
+        let mut deltas = Vec::new();
+        for i in 0..4 {
+            let f = (n * 7 + i * 13) % FILES;
+            deltas.push("M".to_string());
+            deltas.push(name(f, versions[f]));
+        }
+        let f = n % FILES;
+        deltas.push("R100".to_string());
+        deltas.push(name(f, versions[f]));
+        versions[f] += 1;
+        deltas.push(name(f, versions[f]));
+        commits.push(deltas);
+    }
+    let log = generate(COMMITS, |n| commits[n].clone());
+    let paths: PathSet = versions.iter().enumerate()
+        .map(|(f, &v)| path_from_bytes(name(f, v).into_bytes()))
+        .collect();
+
+    bench("paths: 10,000 commits over 5,000 files", || {
+        let (tx, rx) = sync_channel(64);
+        let tree = thread::scope(|s| {
+            s.spawn(|| {
+                parse_log(&log[..], &LogOptions::new(), &tx).unwrap();
+                drop(tx);
+            });
+            gather_history_with(&paths, |_: &NodeContext| (), |_: &NodeContext| true,
+                                GatherOptions::new(), &rx).unwrap()
+        });
+        assert_eq!(tree.len(), FILES);
+    });
+}
+
+fn main() {
+    wide_commits();
+    many_commits();
+    many_paths();
+}
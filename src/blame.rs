@@ -0,0 +1,217 @@
+//! Line-by-line history of a file at some commit, from `git blame`
+//!
+//! This pairs nicely with a `Visitor` (see the [history](../history/index.html)
+//! module) that attaches each change's blame as its data,
+//! though it runs Git once per node, so it's not quick.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str;
+
+use types::{Change, GitPathRef, HistoryNode, SHA1, path_to_os};
+
+use parsing::git_program;
+
+/// Git calls a file binary if it has a NUL in its first this-many bytes.
+const BINARY_CHECK_LEN: usize = 8000;
+
+/// A line of a file, and who last changed it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlameLine {
+    /// The commit that last changed the line
+    pub sha: SHA1,
+    /// That commit's author
+    pub author: String,
+    /// The line's number in the file (starting at 1)
+    pub line_no: usize,
+    /// The line, without its newline
+    pub content: String,
+}
+
+/// Blames each line of `path` as of commit `rev` in `repo`
+/// (with `git blame --porcelain`)
+///
+/// `SHA1::NULL` (see `parsing::LogOptions::working_tree()`)
+/// blames the file in the working tree instead.
+/// Binary files have no lines to speak of, so they get `None`.
+/// Blaming a path that doesn't exist as of `rev` is an error,
+/// as is any other Git failure.
+pub fn blame(repo: &Path, rev: SHA1, path: &GitPathRef) -> io::Result<Option<Vec<BlameLine>>> {
+    let mut command = Command::new(git_program());
+    command.arg("-C").arg(repo)
+        .arg("blame").arg("--porcelain");
+    if rev != SHA1::NULL {
+        command.arg(rev.to_string());
+    }
+    let mut child = command.arg("--").arg(path_to_os(path)?)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // If we stop reading early, closing the pipe stops Git.
+    let parsed = parse_porcelain(BufReader::new(child.stdout.take().unwrap()));
+    let output = child.wait_with_output()?;
+
+    let complaint = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    match parsed {
+        // We gave up on a binary file, so Git's exit status doesn't matter.
+        Ok(None) => Ok(None),
+        _ if !output.status.success() && !complaint.is_empty() => Err(io::Error::other(complaint)),
+        Ok(Some(_)) if !output.status.success() =>
+            Err(io::Error::other(format!("git blame failed ({})", output.status))),
+        p => p
+    }
+}
+
+/// Blames the file as of the given node (see `blame()`)
+///
+/// Deletions leave nothing to blame at their own commit, so they get `None`.
+pub fn blame_node<T>(repo: &Path, node: &HistoryNode<T>) -> io::Result<Option<Vec<BlameLine>>> {
+    if node.change == Change::Deleted {
+        return Ok(None);
+    }
+    blame(repo, node.id, &node.path)
+}
+
+/// Parses `git blame --porcelain` output
+///
+/// Each line gets a header - `<sha> <original line> <final line>`, plus the
+/// number of lines in the group for the first line of a group - followed by
+/// info about the commit, then the line itself after a tab.
+/// The commit info is only given the first time the commit shows up,
+/// so we have to remember each commit's author.
+/// (`--line-porcelain` repeats it every time, which is fine too.)
+fn parse_porcelain<R: BufRead>(mut reader: R) -> io::Result<Option<Vec<BlameLine>>> {
+    let bad = |line: &[u8]| io::Error::new(io::ErrorKind::InvalidData,
+        format!("Unexpected git blame output: {:?}", String::from_utf8_lossy(line)));
+
+    let mut authors = HashMap::new();
+    let mut lines = Vec::new();
+    let mut checked = 0;
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+
+        // <sha> <original line> <final line> [<lines in group>]
+        let header = str::from_utf8(&line).map_err(|_| bad(&line))?;
+        let mut fields = header.split_whitespace();
+        let sha = fields.next().and_then(|s| SHA1::parse(s).ok()).ok_or_else(|| bad(&line))?;
+        let line_no = fields.nth(1).and_then(|n| n.parse().ok()).ok_or_else(|| bad(&line))?;
+
+        // Then key-value pairs about the commit until the line itself
+        let content = loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "git blame ended partway through a line"));
+            }
+            if line.first() == Some(&b'\t') {
+                break line[1..].strip_suffix(b"\n").unwrap_or(&line[1..]).to_vec();
+            }
+            if let Some(name) = line.strip_prefix(b"author ") {
+                let name = name.strip_suffix(b"\n").unwrap_or(name);
+                authors.insert(sha, String::from_utf8_lossy(name).into_owned());
+            }
+        };
+
+        if checked < BINARY_CHECK_LEN {
+            let len = (BINARY_CHECK_LEN - checked).min(content.len());
+            if content[..len].contains(&0) {
+                return Ok(None);
+            }
+            checked += content.len() + 1;
+        }
+
+        lines.push(BlameLine{ sha,
+                              author: authors.get(&sha).cloned().unwrap_or_default(),
+                              line_no,
+                              content: String::from_utf8_lossy(&content).into_owned() });
+    }
+    Ok(Some(lines))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::*;
+
+    /// Porcelain output for a three-line file: two lines from commit 1
+    /// (the second without the commit's info, since Git already gave it),
+    /// then a group from commit 2
+    fn porcelain() -> String {
+        format!("{one} 1 1 2\n\
+                 author Alice\n\
+                 author-mail <alice@example.com>\n\
+                 summary First\n\
+                 filename f\n\
+                 \tline one\n\
+                 {one} 2 2\n\
+                 \tline two\n\
+                 {two} 2 3 1\n\
+                 author Bob\n\
+                 summary Second\n\
+                 previous {one} f\n\
+                 filename f\n\
+                 \tline three\n",
+                one = sha(1), two = sha(2))
+    }
+
+    #[test]
+    fn parses_porcelain() {
+        let lines = parse_porcelain(porcelain().as_bytes()).unwrap().unwrap();
+        let got: Vec<_> = lines.iter()
+            .map(|l| (l.sha, l.author.as_str(), l.line_no, l.content.as_str()))
+            .collect();
+        assert_eq!(got, [(sha(1), "Alice", 1, "line one"),
+                         (sha(1), "Alice", 2, "line two"),
+                         (sha(2), "Bob", 3, "line three")]);
+    }
+
+    #[test]
+    fn gives_up_on_binary_files() {
+        let binary = porcelain().replace("line two", "line\0two");
+        assert!(parse_porcelain(binary.as_bytes()).unwrap().is_none());
+
+        // Past the first 8000 bytes, a NUL is just a NUL.
+        let mut late = format!("{} 1 1 1\nauthor Alice\n\t{}\n",
+                               sha(1), "x".repeat(BINARY_CHECK_LEN));
+        late.push_str(&format!("{} 2 2\n\tnul\0here\n", sha(1)));
+        assert_eq!(parse_porcelain(late.as_bytes()).unwrap().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_truncated_porcelain() {
+        let truncated = format!("{} 1 1 1\nauthor Alice\n", sha(1));
+        assert_eq!(parse_porcelain(truncated.as_bytes()).unwrap_err().kind(),
+                   io::ErrorKind::UnexpectedEof);
+        assert_eq!(parse_porcelain(&b"garbage\n"[..]).unwrap_err().kind(),
+                   io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn blames_commits_and_the_working_tree() {
+        let repo = Repo::new("blame");
+        repo.write("f", "one\n");
+        repo.git(&["add", "f"]);
+        repo.git(&["commit", "-qm", "Add f"]);
+        let head = repo.rev_parse("HEAD");
+        repo.write("f", "one\ntwo\n");
+
+        let committed = blame(&repo.path, head, &path("f")).unwrap().unwrap();
+        assert_eq!(committed.len(), 1);
+        assert_eq!((committed[0].sha, committed[0].author.as_str()), (head, "Test"));
+
+        let working = blame(&repo.path, SHA1::NULL, &path("f")).unwrap().unwrap();
+        let shas: Vec<_> = working.iter().map(|l| l.sha).collect();
+        assert_eq!(shas, [head, SHA1::NULL]);
+        assert_eq!(working[1].content, "two");
+
+        assert!(blame(&repo.path, head, &path("missing")).is_err());
+    }
+}
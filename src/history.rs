@@ -2,17 +2,15 @@
 //!
 //! The basic algorithm is as follows: given a set of paths we care about and a
 //! series of commits (provided by the [parsing](../parsing/index.html) module),
-//! do the following for each commit:
-//!
-//! 1. Call the user-provided filter to see if the user cares about this commit.
-//!    If they do, call the user-provided callback to extract information.
-//!    The callback can use the data provided by `ParsedCommit`, or it
-//!    can gather its own info using the commit's SHA1 ID and git commands.
-//!    (The latter is, of course, much slower.)
-//!
-//! 2. Then, for each added/removed/changed/etc. file in the commit,
+//! do the following for each added/removed/changed/etc. file in each commit:
 //!
 //!    - Create a new node representing the delta.
+//!      Call the user-provided filter to see if the user cares about this
+//!      change, and if so, call the user-provided `Visitor`
+//!      to extract information. Both can use the data provided by
+//!      `NodeContext` (the commit and the delta), or the visitor can gather
+//!      its own info using the commit's SHA1 ID and git commands.
+//!      (The latter is, of course, much slower.)
 //!
 //!    - Connect it to previous nodes using the "pending edges" map
 //!      (see the next step).
@@ -26,121 +24,863 @@
 //!
 //! The net effect is that files' histories are tracked *through* name changes,
 //! a la `git log --follow`.
-//! Currently the act of renaming a file is considered a change, even though
+//! Every node waiting on a path gets linked to that path's next (older) node,
+//! so files copied from the same source share the source's nodes instead of
+//! getting copies of them. Each branch is a list, but the tree as a whole
+//! is a graph where those branches converge.
+//! If a commit has several deltas for the same path, only the first one counts.
+//!
+//! By default, the act of renaming a file is considered a change, even though
 //! the actual contents haven't changed at all.
 //! (This seems to be consistent with `git log --follow`).
-//! If, in the future, this is not desired, we *do* track the amount a file has
-//! been changed during a rename, and could skip adding a node if no changes are
-//! made to the contents.
+//! `GatherOptions::skip_pure_renames()` skips adding a node if no changes are
+//! made to the contents (see `FileDelta::is_pure_rename()`).
+//!
+//! The set of paths can also name directories (`src/` or `src/**`),
+//! which tracks every file under them that's still around in the newest commit.
+//! A file renamed into the directory keeps its history from before the move.
+//! A file renamed out of it is no longer under the directory, so it isn't
+//! tracked at all - add its new path to the set if you want it.
 
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "serde")]
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt::{self, Display, Formatter, Write};
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::sync::mpsc::Receiver;
 use std::rc::Rc;
+use std::time::SystemTime;
+
+use types::{ArenaNode, Branch, Change, FileDelta, GitPath, GitPathRef,
+            HistoryArena, HistoryNode, HistoryTree, Link, NodeData, NodeId,
+            PathSet, SHA1, display_path,
+            path_bytes, path_from_bytes, path_to_os};
+
+use parsing::{CommitIter, DeltaPool, LogOptions, ParseError, ParsedCommit, git_program};
+
+
+/// Everything a `Visitor` gets to know about the change it's visiting
+pub struct NodeContext<'a> {
+    /// The commit that made the change
+    pub commit: &'a ParsedCommit,
+
+    /// The change to the file this node is for
+    pub delta: &'a FileDelta,
+
+    /// The file's path as of this change (same as `delta.path`)
+    pub path: &'a GitPathRef,
+
+    /// The kind of change (same as `delta.change`)
+    pub change: Change,
+}
+
+/// Gathers the user's data `T` for each node in the history
+///
+/// Any `Fn(&NodeContext) -> T` is a `Visitor`, but implementing it yourself
+/// is handy if your visitor has state (say, a database handle).
+pub trait Visitor<T> {
+    fn visit(&self, ctx: &NodeContext) -> T;
+}
+
+impl<T, F> Visitor<T> for F where F: Fn(&NodeContext) -> T {
+    fn visit(&self, ctx: &NodeContext) -> T { self(ctx) }
+}
+
+/// A `Visitor` that only calls `f` once per commit (see `per_commit()`)
+pub struct PerCommit<T, F> {
+    f: F,
+    last: RefCell<Option<(SHA1, T)>>,
+}
+
+/// Wraps `f`, which gathers info about a whole commit, into a `Visitor`
+/// that calls it once per commit instead of once per change
+///
+/// This is a big win if `f` has to shell out to Git, since a commit that
+/// touches several files we're tracking would otherwise be asked about
+/// once for each of them. Each node gets a clone of the result
+/// (see `GatherOptions::visit_once_per_commit()` to share one instead).
+/// Commits arrive one at a time, so we only need to remember the last one.
+pub fn per_commit<T, F>(f: F) -> PerCommit<T, F>
+    where T: Clone, F: Fn(&ParsedCommit) -> T {
+    PerCommit{ f, last: RefCell::new(None) }
+}
+
+impl<T, F> Visitor<T> for PerCommit<T, F>
+    where T: Clone, F: Fn(&ParsedCommit) -> T {
+    fn visit(&self, ctx: &NodeContext) -> T {
+        let mut last = self.last.borrow_mut();
+        match *last {
+            Some((id, ref t)) if id == ctx.commit.id => return t.clone(),
+            _ => { }
+        }
+        let t = (self.f)(ctx.commit);
+        *last = Some((ctx.commit.id, t.clone()));
+        t
+    }
+}
+
+/// See `GatherOptions::on_branch_start()`
+type BranchStartFn<'a, T> = Box<dyn FnMut(&GitPathRef, &Link<HistoryNode<T>>) + 'a>;
+
+/// See `GatherOptions::stop_when()`
+type StopFn<'a> = Box<dyn FnMut(&ParsedCommit) -> bool + 'a>;
+
+/// Optional knobs for `gather_history_with()`
+///
+/// The defaults reproduce the behavior of plain `gather_history()`.
+pub struct GatherOptions<'a, T> {
+    map_commit: Option<Box<dyn FnMut(ParsedCommit) -> ParsedCommit + 'a>>,
+    on_branch_start: Option<BranchStartFn<'a, T>>,
+    on_progress: Option<Box<dyn FnMut(usize) + 'a>>,
+    stop_when: Option<StopFn<'a>>,
+    skip_binary: bool,
+    skip_pure_renames: bool,
+    copy_threshold: u8,
+    rename_threshold: u8,
+    follow_resurrections: bool,
+    record_cochanges: bool,
+    visit_once_per_commit: bool,
+    max_open_branches: Option<usize>,
+    case_insensitive: bool,
+    delta_pool: Option<DeltaPool>,
+    subtree_root: Option<GitPath>,
+}
+
+// Derived Default would want T: Default.
+impl<'a, T> Default for GatherOptions<'a, T> {
+    fn default() -> GatherOptions<'a, T> {
+        GatherOptions {
+            map_commit: None,
+            on_branch_start: None,
+            on_progress: None,
+            stop_when: None,
+            skip_binary: false,
+            skip_pure_renames: false,
+            copy_threshold: 0,
+            rename_threshold: 0,
+            follow_resurrections: false,
+            record_cochanges: false,
+            visit_once_per_commit: false,
+            max_open_branches: None,
+            case_insensitive: false,
+            delta_pool: None,
+            subtree_root: None,
+        }
+    }
+}
+
+impl<'a, T> GatherOptions<'a, T> {
+    pub fn new() -> GatherOptions<'a, T> { GatherOptions::default() }
+
+    /// Passes each commit through `m` as it arrives from the parser,
+    /// before it's appended to the tree.
+    ///
+    /// This is a handy spot to enrich commits or drop deltas you don't care
+    /// about without another pass over the history.
+    /// The identity transform is used if this isn't set.
+    pub fn map_commit<M>(&mut self, m: M) -> &mut GatherOptions<'a, T>
+        where M: FnMut(ParsedCommit) -> ParsedCommit + 'a {
+        self.map_commit = Some(Box::new(m));
+        self
+    }
+
+    /// Calls `f` with each branch's path and head as soon as we've found it,
+    /// i.e., when the path's newest change becomes a key in the tree.
+    ///
+    /// This is handy for progress reports ("now tracking src/foo.rs"),
+    /// since the walk can take a while on a big repo and its results
+    /// otherwise only show up at the end.
+    /// Keep in mind that the head's history is still being gathered.
+    pub fn on_branch_start<B>(&mut self, f: B) -> &mut GatherOptions<'a, T>
+        where B: FnMut(&GitPathRef, &Link<HistoryNode<T>>) + 'a {
+        self.on_branch_start = Some(Box::new(f));
+        self
+    }
+
+    /// Calls `f` with the number of commits we've gone through so far
+    /// after each one, for progress bars and such.
+    ///
+    /// We don't know how many commits there are until we run out,
+    /// so if you want a percentage, ask Git first
+    /// (`git rev-list --count HEAD`).
+    pub fn on_progress<P>(&mut self, f: P) -> &mut GatherOptions<'a, T>
+        where P: FnMut(usize) + 'a {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Stops the walk once `s` returns true for a commit we just added,
+    /// returning the tree gathered so far.
+    ///
+    /// Handy for "the last few changes to this file" without parsing the whole
+    /// history. Branches that got cut off just end early, as if the file was
+    /// added there, and show up in `Gathered::unresolved`.
+    ///
+    /// If you're getting commits from a channel, drop the `Receiver` once we're
+    /// done so the parsing thread notices and stops Git.
+    pub fn stop_when<S>(&mut self, s: S) -> &mut GatherOptions<'a, T>
+        where S: FnMut(&ParsedCommit) -> bool + 'a {
+        self.stop_when = Some(Box::new(s));
+        self
+    }
+
+    /// Creates no nodes for changes to binary files.
+    ///
+    /// Edges are still threaded through the skipped changes,
+    /// so a text file that was renamed while binary (oddly enough)
+    /// keeps its history.
+    /// This needs `--numstat` output (see `parsing::LogOptions::numstat()`) -
+    /// without it we don't know what's binary, and nothing is skipped.
+    pub fn skip_binary(&mut self, enable: bool) -> &mut GatherOptions<'a, T> {
+        self.skip_binary = enable;
+        self
+    }
+
+    /// Creates no nodes for renames that didn't change the file's contents
+    /// (see `FileDelta::is_pure_rename()`).
+    ///
+    /// The file's history carries on under its old name as if the rename
+    /// weren't there, so each node is a real change to the contents.
+    /// The branch is still keyed by the newest name.
+    pub fn skip_pure_renames(&mut self, enable: bool) -> &mut GatherOptions<'a, T> {
+        self.skip_pure_renames = enable;
+        self
+    }
+
+    /// Only follows copies at least `percent` similar to their source.
+    ///
+    /// Git reports anything over its own threshold (50% by default) as a copy,
+    /// but a file that shares half its lines with another often isn't
+    /// really the same file. A copy below `percent` gets a node with no
+    /// previous change, so the file's history starts there, as if it were
+    /// added. Every copy is followed by default.
+    pub fn copy_threshold(&mut self, percent: u8) -> &mut GatherOptions<'a, T> {
+        self.copy_threshold = percent;
+        self
+    }
+
+    /// Like `copy_threshold()`, but for renames
+    pub fn rename_threshold(&mut self, percent: u8) -> &mut GatherOptions<'a, T> {
+        self.rename_threshold = percent;
+        self
+    }
+
+    /// Threads a file's history through a deletion if it's later re-added
+    /// with the exact same contents.
+    ///
+    /// Git considers the deleted and re-added files unrelated (and by default,
+    /// so do we), but this is usually what you want when someone deletes a
+    /// file by accident and brings it back a few commits later.
+    /// It's a heuristic - two unrelated files with identical contents at the
+    /// same path will be linked too - so it's off by default.
+    /// This needs blob IDs (see `parsing::LogOptions::blobs()`).
+    pub fn follow_resurrections(&mut self, enable: bool) -> &mut GatherOptions<'a, T> {
+        self.follow_resurrections = enable;
+        self
+    }
+
+    /// Records on each node the other paths changed in the same commit
+    /// (see `HistoryNode::cochanged_paths()`),
+    /// for finding files that tend to change together.
+    ///
+    /// This copies every path of every commit once per tracked file it changes,
+    /// so it's off by default.
+    pub fn record_cochanges(&mut self, enable: bool) -> &mut GatherOptions<'a, T> {
+        self.record_cochanges = enable;
+        self
+    }
+
+    /// Calls the visitor for just the first change in each commit that the
+    /// filter lets through, and gives the commit's other nodes the same `Rc`
+    /// as their `data`.
+    ///
+    /// This is for visitors that only look at `NodeContext::commit`
+    /// (say, looking up the commit's author), so a commit that changes
+    /// five files we're tracking isn't visited five times.
+    /// If your visitor's result depends on the delta, leave this off.
+    /// (Or see `per_commit()`, which gives each node its own copy.)
+    /// Arenas hold each node's data by value, so
+    /// `gather_history_arena()` ignores this.
+    pub fn visit_once_per_commit(&mut self, enable: bool) -> &mut GatherOptions<'a, T> {
+        self.visit_once_per_commit = enable;
+        self
+    }
+
+    /// Gives up with `GatherError::TooManyOpenBranches` if more than `limit`
+    /// paths are waiting on older history at once.
+    ///
+    /// A path is waiting once we have a change that links to its next (older)
+    /// one: a file we've seen modified but haven't yet seen added, or the old
+    /// name of a renamed or copied file. On a pathological repo, the old names
+    /// can pile up without bound. Tracked paths we haven't come across yet
+    /// don't count. There's no limit by default.
+    pub fn max_open_branches(&mut self, limit: Option<usize>) -> &mut GatherOptions<'a, T> {
+        self.max_open_branches = limit;
+        self
+    }
+
+    /// Starts no new branch for a path that only differs in case from one
+    /// we already have a branch for.
+    ///
+    /// A case-only rename (`Foo.rs` to `foo.rs`) is followed like any other
+    /// rename, but on a case-insensitive filesystem (macOS, Windows),
+    /// the set of paths we're given can easily have the old spelling too.
+    /// Normally we'd take it at its word and start a second branch for it
+    /// from the commits before the rename. With this set, only the newest
+    /// spelling gets a branch, and it carries the whole history.
+    /// Only ASCII letters are compared case-insensitively.
+    pub fn case_insensitive(&mut self, enable: bool) -> &mut GatherOptions<'a, T> {
+        self.case_insensitive = enable;
+        self
+    }
+
+    /// Only follows renames and copies as long as the file stays under
+    /// the directory `root`, e.g. `src/net`.
+    ///
+    /// A branch for a file that was renamed or copied in from elsewhere ends
+    /// with that change, as if it were added then.
+    /// Pair this with `parsing::tracked_files_under()` to track everything
+    /// in the subtree.
+    pub fn subtree_root(&mut self, root: Option<GitPath>) -> &mut GatherOptions<'a, T> {
+        self.subtree_root = root;
+        self
+    }
 
-use types::{Change, HistoryNode, HistoryTree, Link, PathSet};
-use parsing::ParsedCommit;
+    /// Hands each commit's deltas back to the parser once we're done with
+    /// them, so it can reuse the allocation
+    /// (see `parsing::LogOptions::delta_pool()`).
+    pub fn recycle_deltas(&mut self, pool: Option<DeltaPool>) -> &mut GatherOptions<'a, T> {
+        self.delta_pool = pool;
+        self
+    }
+}
+
+/// Ways `gather_history_with()` can fail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GatherError {
+    /// More paths than `GatherOptions::max_open_branches()` allowed
+    /// were waiting on older history.
+    TooManyOpenBranches{ limit: usize },
+}
+
+impl Error for GatherError { }
+
+impl Display for GatherError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            GatherError::TooManyOpenBranches{ limit } =>
+                write!(f, "More than {} paths were waiting on older history", limit),
+        }
+    }
+}
+
+/// Where `HistoryState` puts the nodes it makes:
+/// `Rc`s for a `HistoryTree`, or a list for a `HistoryArena`
+trait NodeStore<T> {
+    /// How nodes refer to each other
+    type Handle: Clone;
+
+    /// Can nodes share their data? (See `GatherOptions::visit_once_per_commit()`)
+    const SHARES_DATA: bool;
+
+    /// Adds a node that isn't linked to anything yet
+    fn add(&mut self, node: NodeData<Rc<T>>, cochanged: Vec<GitPath>) -> Self::Handle;
+
+    /// Makes `to` the previous change of `node`, unless it already has one
+    /// (see `link_previous()`)
+    fn link(&mut self, node: &Self::Handle, to: &Self::Handle);
+
+    fn same(a: &Self::Handle, b: &Self::Handle) -> bool;
+
+    /// Lets the user know we found a branch (see `GatherOptions::on_branch_start()`)
+    fn announce(f: &mut BranchStartFn<'_, T>, key: &GitPathRef, head: &Self::Handle);
+}
+
+/// Nodes for a `HistoryTree`, each in its own `Rc`
+struct RcNodes;
+
+impl<T> NodeStore<T> for RcNodes {
+    type Handle = Link<HistoryNode<T>>;
+
+    const SHARES_DATA: bool = true;
+
+    fn add(&mut self, node: NodeData<Rc<T>>, cochanged: Vec<GitPath>) -> Link<HistoryNode<T>> {
+        Rc::new(RefCell::new(HistoryNode{data: node.data,
+                                         id: node.id,
+                                         when: node.when,
+                                         author: node.author,
+                                         path: node.path,
+                                         change: node.change,
+                                         blob: node.blob,
+                                         previous: None,
+                                         next: Vec::new(),
+                                         cochanged}))
+    }
+
+    fn link(&mut self, node: &Link<HistoryNode<T>>, to: &Link<HistoryNode<T>>) {
+        link_previous(node, to);
+    }
+
+    fn same(a: &Link<HistoryNode<T>>, b: &Link<HistoryNode<T>>) -> bool { Rc::ptr_eq(a, b) }
+
+    fn announce(f: &mut BranchStartFn<'_, T>, key: &GitPathRef, head: &Link<HistoryNode<T>>) {
+        f(key, head);
+    }
+}
+
+/// Nodes for a `HistoryArena`
+impl<T> NodeStore<T> for Vec<ArenaNode<T>> {
+    type Handle = NodeId;
+
+    // Arena nodes hold their data by value.
+    const SHARES_DATA: bool = false;
+
+    fn add(&mut self, node: NodeData<Rc<T>>, cochanged: Vec<GitPath>) -> NodeId {
+        // Since nothing is shared, we have the only reference.
+        let data = node.data.map(|d| Rc::try_unwrap(d).ok().expect("Arena node data was shared"));
+        let node = NodeData{ data,
+                             id: node.id,
+                             when: node.when,
+                             author: node.author,
+                             path: node.path,
+                             change: node.change,
+                             blob: node.blob };
+        self.push(ArenaNode{ node, previous: None, next: Vec::new(), cochanged });
+        NodeId(self.len() - 1)
+    }
+
+    fn link(&mut self, node: &NodeId, to: &NodeId) {
+        if self[node.0].previous.is_none() {
+            self[node.0].previous = Some(*to);
+            self[to.0].next.push(*node);
+        }
+    }
 
+    fn same(a: &NodeId, b: &NodeId) -> bool { a == b }
+
+    // There's no Link to hand out.
+    fn announce(_: &mut BranchStartFn<'_, T>, _: &GitPathRef, _: &NodeId) { }
+}
 
 /// All the fun state we need to hang onto while building up our history tree.
 /// Forgive the template param stew. All it's doing is allowing the user to
-/// use an arbitrary function `F` to filter commits, then use an arbitrary
-/// function `V` to gather arbitrary data `T` from each commit.
-struct HistoryState<'a, T, V, F>
-    where V: Fn(&ParsedCommit) -> T, F: Fn(&ParsedCommit) -> bool {
-    /// The tree we'll return
-    history: HistoryTree<T>,
+/// use an arbitrary function `F` to filter changes, then use an arbitrary
+/// visitor `V` to gather arbitrary data `T` from each change,
+/// and storing the nodes in `S`.
+struct HistoryState<'a, T, V, F, S>
+    where V: Visitor<T>, F: Fn(&NodeContext) -> bool, S: NodeStore<T> {
+    /// The tree we'll return (or the arena's heads)
+    history: HashMap<GitPath, S::Handle>,
+
+    /// Where the nodes live
+    nodes: S,
 
     /// History is generated by noting (from the diff type) what a file's
     /// name was during its previous change.
     /// Those edges (between HistoryNodes) are stored here, where
     /// pending_edges[p] lists all nodes that should be connected to the next
     /// node for path `p`.
-    pending_edges: HashMap<String, Vec<Link<HistoryNode<T>>>>,
+    pending_edges: HashMap<PathId, Vec<S::Handle>>,
+
+    /// When we skip a rename or copy without creating a node for it,
+    /// the branch whose head would have been that node should get its head
+    /// from the next node under the old path instead.
+    /// redirected_heads[p] lists the tree keys waiting on the next node for `p`.
+    redirected_heads: HashMap<PathId, Vec<PathId>>,
+
+    /// If we're following resurrections, these are files that were added,
+    /// keyed by path and blob ID, which should be linked to an older deletion
+    /// of the same contents if we come across one.
+    resurrections: HashMap<(PathId, SHA1), Vec<S::Handle>>,
+
+    /// The paths the maps above are keyed by
+    paths: PathIds,
 
     /// Hold a reference to which paths we care about, for culling output.
     path_set: &'a PathSet,
 
-    /// The user-provided callback that's issued for each diff,
+    /// The directories in `path_set` (see `dir_entry()`)
+    tracked_dirs: Vec<Vec<u8>>,
+
+    /// Files under `tracked_dirs` that exist as of the newest commit,
+    /// which get branches like the files named in `path_set`
+    dir_files: HashSet<PathId>,
+
+    /// Every path under `tracked_dirs` we've come across,
+    /// so that we only decide whether to track each one once
+    dir_seen: HashSet<PathId>,
+
+    /// The tree's keys with ASCII letters lowercased, if we're comparing them
+    /// that way (see `GatherOptions::case_insensitive()`)
+    folded_keys: HashSet<Vec<u8>>,
+
+    /// The user-provided visitor that's called for each diff,
     /// returning info the user cares about.
     visitor: V,
 
     filter: F,
-}
 
-fn new_node<T>(d: Option<Rc<T>>) -> Link<HistoryNode<T>> {
-    Rc::new(RefCell::new(HistoryNode{data: d, previous: None}))
+    /// The visitor's result for the last commit we visited
+    /// (see `GatherOptions::visit_once_per_commit()`)
+    commit_data: Option<(SHA1, Rc<T>)>,
+
+    options: GatherOptions<'a, T>,
 }
 
-impl<'a, T, V, F> HistoryState<'a, T, V, F>
-    where V: Fn(&ParsedCommit) -> T, F: Fn(&ParsedCommit) -> bool {
 
-    fn new(set: &'a PathSet, vis: V, fil: F) -> HistoryState<T, V, F> {
+impl<'a, T, V, F, S> HistoryState<'a, T, V, F, S>
+    where V: Visitor<T>, F: Fn(&NodeContext) -> bool, S: NodeStore<T> {
+
+    fn new(set: &'a PathSet, vis: V, fil: F, opts: GatherOptions<'a, T>, nodes: S)
+        -> HistoryState<'a, T, V, F, S> {
         let mut pending = HashMap::new();
+        let mut paths = PathIds::default();
 
         // Due to the check at the start of append_commit(), we must insert
         // entries into pending_edges so that we care about the first diff found
         // for a given file.
+        // Directories get theirs as we find the files in them.
+        let mut dirs = Vec::new();
         for path in set {
-            pending.insert(path.clone(), Vec::new());
+            match dir_entry(path) {
+                Some(d) => dirs.push(d.to_vec()),
+                None => { pending.insert(paths.intern(path), Vec::new()); }
+            }
         }
 
-        HistoryState{ history: HistoryTree::new(),
+        HistoryState{ history: HashMap::new(),
+                      nodes,
                       pending_edges: pending,
+                      redirected_heads: HashMap::new(),
+                      resurrections: HashMap::new(),
+                      paths,
                       path_set: set,
+                      tracked_dirs: dirs,
+                      dir_files: HashSet::new(),
+                      dir_seen: HashSet::new(),
+                      folded_keys: HashSet::new(),
                       visitor: vis,
-                      filter: fil
+                      filter: fil,
+                      commit_data: None,
+                      options: opts
                     }
     }
 
-    /// Takes a given commit and appends its changes to the history tree
-    fn append_commit(&mut self, commit: &ParsedCommit) {
+    /// Runs the user's `map_commit` transform, if any, on the given commit.
+    fn map_commit(&mut self, commit: ParsedCommit) -> ParsedCommit {
+        match self.options.map_commit {
+            Some(ref mut m) => m(commit),
+            None => commit
+        }
+    }
+
+    /// Appends each commit from `commits` until we run out
+    fn consume<I>(&mut self, commits: I) -> Result<(), GatherError>
+        where I: IntoIterator<Item = ParsedCommit> {
+        for (count, commit) in commits.into_iter().enumerate() {
+            let commit = self.map_commit(commit);
+            self.append_commit(&commit);
+
+            if let Some(ref mut f) = self.options.on_progress {
+                f(count + 1);
+            }
+
+            let stop = self.options.stop_when.as_mut().is_some_and(|s| s(&commit));
+
+            if let Some(ref pool) = self.options.delta_pool {
+                pool.recycle(commit.deltas);
+            }
+
+            if let Some(limit) = self.options.max_open_branches {
+                // Paths we haven't reached yet have no edges waiting,
+                // but only count them if we could be over.
+                if self.pending_edges.len() > limit &&
+                   self.pending_edges.values().filter(|e| !e.is_empty()).count() > limit {
+                    return Err(GatherError::TooManyOpenBranches{ limit });
+                }
+            }
+
+            if stop {
+                break;
+            }
+        }
+        Ok(())
+    }
 
-        // For each commit, see if we care to extract data,
-        // and possibly do so.
-        let data = if (self.filter)(commit) {
-                Some(Rc::new((self.visitor)(commit)))
+    /// Creates a node for the given delta,
+    /// calling the visitor to fill it in if the filter wants it
+    fn new_node(&mut self, commit: &ParsedCommit, delta: &FileDelta) -> S::Handle {
+        let ctx = NodeContext{ commit,
+                               delta,
+                               path: &delta.path,
+                               change: delta.change };
+        let data = if (self.filter)(&ctx) {
+                Some(self.visit(&ctx))
             }
             else {
                 None
             };
 
+        let cochanged = if self.options.record_cochanges {
+                commit.deltas.iter()
+                    .filter(|d| d.path != delta.path)
+                    .map(|d| d.path.clone())
+                    .collect()
+            }
+            else {
+                Vec::new()
+            };
+
+        self.nodes.add(NodeData{ data,
+                                 id: commit.id,
+                                 when: commit.when,
+                                 author: commit.author.clone(),
+                                 path: delta.path.clone(),
+                                 change: delta.change,
+                                 blob: delta.blob() },
+                       cochanged)
+    }
+
+    /// Calls the visitor for `ctx`, or reuses its result from an earlier change
+    /// in the same commit if we're only visiting each commit once
+    fn visit(&mut self, ctx: &NodeContext) -> Rc<T> {
+        if !(self.options.visit_once_per_commit && S::SHARES_DATA) {
+            return Rc::new(self.visitor.visit(ctx));
+        }
+
+        match self.commit_data {
+            Some((id, ref data)) if id == ctx.commit.id => return data.clone(),
+            _ => { }
+        }
+        let data = Rc::new(self.visitor.visit(ctx));
+        self.commit_data = Some((ctx.commit.id, data.clone()));
+        data
+    }
+
+    /// Takes a given commit and appends its changes to the history tree
+    fn append_commit(&mut self, commit: &ParsedCommit) {
+        // Paths we've already made (or skipped) a node for in this commit
+        let mut done = HashSet::new();
+
         for delta in &commit.deltas {
 
+            // If this deletion is one of our resurrected files being deleted,
+            // its additions are waiting on it.
+            let resurrected = self.options.follow_resurrections &&
+                              delta.change == Change::Deleted &&
+                              self.resurrect(delta);
+
+            if !self.tracked_dirs.is_empty() {
+                self.discover(delta);
+            }
+
             // If we have no edges leading to the next node for this path,
             // skip to the next diff.
-            if !self.pending_edges.contains_key(&delta.path) {
+            let path = match self.paths.get(&delta.path) {
+                Some(p) if self.pending_edges.contains_key(&p) => p,
+                _ => continue
+            };
+
+            // Git shouldn't give us two deltas for the same path in one commit,
+            // but a hand-rolled commit (or a map_commit()) could.
+            // The first one wins, so each commit has one node per path.
+            if !done.insert(path) {
+                continue;
+            }
+
+            if self.options.skip_binary && delta.is_binary() == Some(true) {
+                self.skip_delta(path, delta);
+                continue;
+            }
+
+            if self.options.skip_pure_renames && delta.is_pure_rename() {
+                self.skip_delta(path, delta);
                 continue;
             }
 
-            // Each delta needs its own node, but it can share the data.
-            let new_node = new_node(data.clone());
+            let new_node = self.new_node(commit, delta);
 
             // In all cases where we care about the given path,
             // insert the new node and link its pending_edges to it.
-            self.append_node(&delta.path, new_node.clone());
+            self.append_node(path, new_node.clone());
 
             match delta.change {
                 // If a file was modified, its next node is under the same path.
-                Change::Modified => {
-                    self.pending_edges.entry(delta.path.clone())
-                        .or_insert_with(Vec::new)
+                Change::Modified |
+                Change::TypeChanged => {
+                    self.pending_edges.entry(path)
+                        .or_default()
                         .push(new_node);
                 }
 
-                // If a file was added, it has no next node (that we care about).
+                // If a file was added, it has no next node (that we care about)
+                // unless it's a resurrection of something deleted earlier.
+                Change::Added => {
+                    if let (true, Some(blob)) = (self.options.follow_resurrections,
+                                                 delta.new_blob) {
+                        self.resurrections.entry((path, blob))
+                            .or_default()
+                            .push(new_node);
+                    }
+                }
+
                 // We also don't care about deletions. If a file is deleted,
-                // it didn't make it - at least in that form - to the present.
-                Change::Added |
-                Change::Deleted => { }
+                // it didn't make it - at least in that form - to the present...
+                Change::Deleted => {
+                    // ...unless it came back, in which case we want the history
+                    // leading up to its deletion.
+                    if resurrected {
+                        self.pending_edges.entry(path)
+                            .or_default()
+                            .push(new_node);
+                    }
+                }
 
                 // If a file was moved or copied,
-                // its next node is under the old path.
-                Change::Copied{..} | // TODO: Use % changed
+                // its next node is under the old path
+                // (unless that's outside the subtree we're following,
+                // or the files are too different to call it the same one).
+                Change::Copied{..} |
                 Change::Renamed{..} => {
-                    self.pending_edges.entry(delta.from.clone())
-                        .or_insert_with(Vec::new)
-                        .push(new_node);
+                    if self.in_subtree(&delta.from) && self.similar_enough(delta.change) {
+                        let from = self.paths.intern(&delta.from);
+                        self.pending_edges.entry(from)
+                            .or_default()
+                            .push(new_node);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Starts tracking files under the directories in our path set
+    /// the first time we see them.
+    ///
+    /// We're walking from the newest commit back, so the first change we see
+    /// to a file tells us whether it's still around. If it was deleted
+    /// (or renamed away, in which case its new name is what we'd track),
+    /// it gets no branch, and neither does anything older at that path.
+    fn discover(&mut self, delta: &FileDelta) {
+        match delta.change {
+            // The old name is gone as of this commit.
+            Change::Renamed{..} if self.in_tracked_dir(&delta.from) => {
+                let from = self.paths.intern(&delta.from);
+                self.dir_seen.insert(from);
+            }
+
+            // The source of a copy is still around.
+            Change::Copied{..} if self.in_tracked_dir(&delta.from) => {
+                let from = self.paths.intern(&delta.from);
+                if self.dir_seen.insert(from) {
+                    self.dir_files.insert(from);
+                    self.pending_edges.entry(from).or_default();
+                }
+            }
+
+            _ => { }
+        }
+
+        if !self.in_tracked_dir(&delta.path) {
+            return;
+        }
+
+        let path = self.paths.intern(&delta.path);
+        if self.pending_edges.contains_key(&path) || !self.dir_seen.insert(path) {
+            return;
+        }
+
+        if delta.change != Change::Deleted {
+            self.dir_files.insert(path);
+            self.pending_edges.insert(path, Vec::new());
+        }
+    }
+
+    /// Returns true if `path` is under one of the directories in our path set
+    fn in_tracked_dir(&self, path: &GitPathRef) -> bool {
+        let path = path_bytes(path);
+        self.tracked_dirs.iter().any(|d| path.starts_with(d))
+    }
+
+    /// Returns false if a copy or rename is below the threshold
+    /// we were given to follow it (see `GatherOptions::copy_threshold()`).
+    fn similar_enough(&self, change: Change) -> bool {
+        match change {
+            Change::Copied{ similarity } => similarity >= self.options.copy_threshold,
+            Change::Renamed{ similarity } => similarity >= self.options.rename_threshold,
+            _ => true
+        }
+    }
+
+    /// If `delta` deletes contents we saw re-added later,
+    /// queues the re-added nodes to link to the deletion's node
+    /// and returns true.
+    fn resurrect(&mut self, delta: &FileDelta) -> bool {
+        let key = match (self.paths.get(&delta.path), delta.old_blob) {
+            (Some(p), Some(b)) => (p, b),
+            _ => return false
+        };
+
+        match self.resurrections.remove(&key) {
+            Some(added) => {
+                self.pending_edges.entry(key.0)
+                    .or_default()
+                    .extend(added);
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Threads pending edges past a delta we aren't creating a node for,
+    /// as if its node were spliced out of the chain.
+    fn skip_delta(&mut self, path: PathId, delta: &FileDelta) {
+        match delta.change {
+            // The next node is under the same path anyway.
+            Change::Modified |
+            Change::TypeChanged => { }
+
+            // Nothing older to thread through to.
+            Change::Added |
+            Change::Deleted => {
+                self.pending_edges.remove(&path);
+                self.redirected_heads.remove(&path);
+            }
+
+            // Ditto if the old path is outside the subtree
+            // or we aren't following the copy or rename.
+            Change::Copied{..} |
+            Change::Renamed{..} if !self.in_subtree(&delta.from) ||
+                                   !self.similar_enough(delta.change) => {
+                self.pending_edges.remove(&path);
+                self.redirected_heads.remove(&path);
+            }
+
+            // Whatever was waiting on this path now waits on the old one.
+            Change::Copied{..} |
+            Change::Renamed{..} => {
+                let from = self.paths.intern(&delta.from);
+                if let Some(edges) = self.pending_edges.remove(&path) {
+                    self.pending_edges.entry(from)
+                        .or_default()
+                        .extend(edges);
+                }
+
+                let mut heads = self.redirected_heads.remove(&path)
+                    .unwrap_or_default();
+                if self.wants_head(path) {
+                    heads.push(path);
+                }
+                if !heads.is_empty() {
+                    self.redirected_heads.entry(from)
+                        .or_default()
+                        .extend(heads);
                 }
             }
         }
@@ -148,29 +888,194 @@ impl<'a, T, V, F> HistoryState<'a, T, V, F>
 
     /// Uses `pending_edges` (via `build_edges()`) to link `node` into
     /// the history tree.
-    fn append_node(&mut self, key: &str, node: Link<HistoryNode<T>>) {
+    fn append_node(&mut self, key: PathId, node: S::Handle) {
         self.build_edges(key, &node);
 
         // If we don't have a node for this path yet, it's the top of the branch.
-        if !self.history.contains_key(key) && self.path_set.contains(key) {
-            self.history.insert(key.to_string(), node);
+        if self.wants_head(key) {
+            self.start_branch(key, &node);
+        }
+
+        // Ditto for branches whose would-be heads were skipped.
+        if let Some(keys) = self.redirected_heads.remove(&key) {
+            for k in keys {
+                if !self.history.contains_key(self.paths.path(k)) {
+                    self.start_branch(k, &node);
+                }
+            }
+        }
+    }
+
+    /// Makes `head` the head of the branch for `key`,
+    /// letting the user know (see `GatherOptions::on_branch_start()`).
+    fn start_branch(&mut self, key: PathId, head: &S::Handle) {
+        let key = self.paths.path(key).clone();
+        if let Some(ref mut f) = self.options.on_branch_start {
+            S::announce(f, &key, head);
+        }
+        if self.options.case_insensitive {
+            self.folded_keys.insert(path_bytes(&key).to_ascii_lowercase());
+        }
+        self.history.insert(key, head.clone());
+    }
+
+    /// Returns true if `path` is under `GatherOptions::subtree_root()`
+    /// (or there isn't one)
+    fn in_subtree(&self, path: &GitPathRef) -> bool {
+        let root = match self.options.subtree_root {
+            Some(ref r) => path_bytes(r),
+            None => return true
+        };
+        let root = root.strip_suffix(b"/").unwrap_or(root);
+        let path = path_bytes(path);
+
+        root.is_empty() ||
+        (path.starts_with(root) && path.get(root.len()) == Some(&b'/'))
+    }
+
+    /// Returns true if `key` is a path we care about that doesn't have
+    /// a branch yet (see `GatherOptions::case_insensitive()`)
+    fn wants_head(&self, id: PathId) -> bool {
+        let key = self.paths.path(id);
+        if self.history.contains_key(key) ||
+           !(self.path_set.contains(key) || self.dir_files.contains(&id)) {
+            return false;
         }
+
+        !(self.options.case_insensitive &&
+          self.folded_keys.contains(&path_bytes(key).to_ascii_lowercase()))
     }
 
     /// Connects older nodes to `link_to` based on `pending_edges`
-    fn build_edges(&mut self, for_path: &str, link_to: &Link<HistoryNode<T>>) {
-        let from_set = match self.pending_edges.remove(for_path) {
+    fn build_edges(&mut self, for_path: PathId, link_to: &S::Handle) {
+        let from_set = match self.pending_edges.remove(&for_path) {
                 None => return, // Bail if there are no changes to link.
                 Some(to_link) => to_link
             };
 
         for l in from_set { // For each rename/copy of <key> to <l>,
-            assert!(l.borrow().previous.is_none());
-            l.borrow_mut().previous = Some(link_to.clone());
+            // link_to is brand new, so the only cycle we could make
+            // is a node linking to itself.
+            if !S::same(&l, link_to) {
+                self.nodes.link(&l, link_to);
+            }
+        }
+    }
+}
+
+impl<'a, T, V, F> HistoryState<'a, T, V, F, RcNodes>
+    where V: Visitor<T>, F: Fn(&NodeContext) -> bool {
+
+    /// Links the oldest nodes we've gathered onto the heads of a tree
+    /// built from older history.
+    ///
+    /// Whatever is still waiting on the next node for path `p` when we run out
+    /// of commits is waiting on `previous[p]`.
+    fn graft_onto(&mut self, previous: &HistoryTree<T>) {
+        let pending = mem::take(&mut self.pending_edges);
+        for (path, edges) in pending {
+            let old_head = match previous.get(self.paths.path(path)) {
+                Some(h) => h,
+                None => continue
+            };
+
+            for l in edges {
+                // The old tree could already lead back to l
+                // if it was built from some of the same nodes.
+                if !BranchIter::new(old_head).any(|n| Rc::ptr_eq(&n, &l)) {
+                    link_previous(&l, old_head);
+                }
+            }
+
+            // If the file hasn't changed since, its head is the old one.
+            if self.wants_head(path) {
+                self.start_branch(path, old_head);
+            }
+        }
+
+        let redirected = mem::take(&mut self.redirected_heads);
+        for (path, keys) in redirected {
+            if let Some(old_head) = previous.get(self.paths.path(path)) {
+                for k in keys {
+                    if !self.history.contains_key(self.paths.path(k)) {
+                        self.start_branch(k, old_head);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Identifies a path in `PathIds`
+type PathId = usize;
+
+/// Numbers each path we come across, so that `HistoryState` can key its maps
+/// by number instead of cloning paths for every change
+#[derive(Default)]
+struct PathIds {
+    ids: HashMap<GitPath, PathId>,
+    paths: Vec<GitPath>,
+}
+
+impl PathIds {
+    /// Returns the ID for `path`, giving it one if it doesn't have one yet
+    fn intern(&mut self, path: &GitPathRef) -> PathId {
+        if let Some(&id) = self.ids.get(path) {
+            return id;
         }
+        let id = self.paths.len();
+        self.paths.push(path.to_owned());
+        self.ids.insert(path.to_owned(), id);
+        id
+    }
+
+    /// Returns the ID for `path`, if it has one
+    fn get(&self, path: &GitPathRef) -> Option<PathId> {
+        self.ids.get(path).cloned()
+    }
+
+    fn path(&self, id: PathId) -> &GitPath { &self.paths[id] }
+}
+
+/// If `entry` in a `PathSet` names a directory (`src/` or `src/**`),
+/// returns it with its trailing slash
+fn dir_entry(entry: &GitPathRef) -> Option<&[u8]> {
+    let bytes = path_bytes(entry);
+    let bytes = bytes.strip_suffix(b"**").unwrap_or(bytes);
+    if bytes.ends_with(b"/") { Some(bytes) } else { None }
+}
+
+/// Returns true if `path` is in `paths`, or under a directory in it
+fn path_set_tracks(paths: &PathSet, path: &GitPathRef) -> bool {
+    paths.contains(path) ||
+    paths.iter()
+        .filter_map(|p| dir_entry(p))
+        .any(|d| path_bytes(path).starts_with(d))
+}
+
+/// Makes `to` the previous change of `node`, unless it already has one.
+///
+/// Odd history can queue up a node for linking more than once. The first link wins, since the nodes
+/// we find later are older.
+fn link_previous<T>(node: &Link<HistoryNode<T>>, to: &Link<HistoryNode<T>>) {
+    if node.borrow().previous.is_none() {
+        set_previous(node, Some(to.clone()));
     }
 }
 
+/// Makes `to` the previous change of `node`, recording `node` as one of
+/// its next changes (see `HistoryNode::next_changes()`)
+fn set_previous<T>(node: &Link<HistoryNode<T>>, to: Option<Link<HistoryNode<T>>>) {
+    let weak = Rc::downgrade(node);
+    if let Some(ref old) = node.borrow().previous {
+        old.borrow_mut().next.retain(|w| !w.ptr_eq(&weak));
+    }
+    if let Some(ref t) = to {
+        t.borrow_mut().next.push(weak);
+    }
+    node.borrow_mut().previous = to;
+}
+
 /// Traverses Git history, grabbing arbitrary data at each change for files
 /// in the given set
 ///
@@ -178,26 +1083,1726 @@ impl<'a, T, V, F> HistoryState<'a, T, V, F>
 /// See the module-level documentation for more info.
 pub fn gather_history<T, V, F>(paths: &PathSet, v: V, f: F,
                                commit_source: &Receiver<ParsedCommit>) -> HistoryTree<T>
-    where V: Fn(&ParsedCommit) -> T, F: Fn(&ParsedCommit) -> bool {
-    let mut state = HistoryState::new(paths, v, f);
-
-    // Start reading commits.
+    where V: Visitor<T>, F: Fn(&NodeContext) -> bool {
+    gather_history_with(paths, v, f, GatherOptions::default(), commit_source)
+        .expect("Gathering with no limits shouldn't fail")
+}
 
-    while let Ok(commit) = commit_source.recv() {
-        state.append_commit(&commit);
-    }
+/// Like `gather_history()`, but with the given `GatherOptions`
+pub fn gather_history_with<'a, T, V, F>(paths: &'a PathSet, v: V, f: F,
+                                        options: GatherOptions<'a, T>,
+                                        commit_source: &Receiver<ParsedCommit>)
+    -> Result<HistoryTree<T>, GatherError>
+    where V: Visitor<T>, F: Fn(&NodeContext) -> bool {
+    gather_history_iter_with(paths, v, f, options, commit_source.iter())
+}
 
-    // We should have consumed all edges by now.
-    // ...but git log --name-status doesn't show the full path of subtree'd files.
-    // TODO: Make the system play well with git subtree.
-    /*
-    if !state.pending_edges.is_empty() {
-        println!("Still have edges for");
-        for key in state.pending_edges.keys() {
-            println!("\t{}", key);
-        }
+/// Like `gather_history()`, but takes commits from any iterator
+/// instead of a channel
+///
+/// This saves spinning up a thread to parse the history
+/// (see `parsing::CommitIter`), and makes it easy to build a tree
+/// from a `Vec<ParsedCommit>` you made yourself.
+/// Commits should come newest first, like `git log` gives them.
+pub fn gather_history_iter<T, V, F, I>(paths: &PathSet, v: V, f: F, commits: I)
+    -> HistoryTree<T>
+    where V: Visitor<T>, F: Fn(&NodeContext) -> bool,
+          I: IntoIterator<Item = ParsedCommit> {
+    gather_history_iter_with(paths, v, f, GatherOptions::default(), commits)
+        .expect("Gathering with no limits shouldn't fail")
+}
+
+/// Like `gather_history_iter()`, but with the given `GatherOptions`
+pub fn gather_history_iter_with<'a, T, V, F, I>(paths: &'a PathSet, v: V, f: F,
+                                                options: GatherOptions<'a, T>,
+                                                commits: I)
+    -> Result<HistoryTree<T>, GatherError>
+    where V: Visitor<T>, F: Fn(&NodeContext) -> bool,
+          I: IntoIterator<Item = ParsedCommit> {
+    gather_history_report(paths, v, f, options, commits).map(|g| g.history)
+}
+
+/// A history tree, along with the paths whose history we couldn't finish
+/// (see `gather_history_report()`)
+pub struct Gathered<T> {
+    pub history: HistoryTree<T>,
+
+    /// Paths (sorted) whose history we were still waiting on when we ran out
+    /// of commits: old names of renamed or copied files that never showed up,
+    /// and paths we were asked to follow that weren't in the log at all
+    pub unresolved: Vec<GitPath>,
+}
+
+/// Like `gather_history_iter_with()`, but also returns the paths whose
+/// history was cut short
+///
+/// Once we've seen every commit, nothing should be waiting on older history
+/// for any path - each file's history should end where it was added.
+/// Whatever is still waiting is listed in `Gathered::unresolved`:
+/// paths we were given that never showed up in the log,
+/// and old names of renamed or copied files that never did either.
+/// That's expected when walking part of the history
+/// (see `parsing::LogOptions::max_count()`), but otherwise it usually means
+/// Git printed paths we didn't expect. For example, `git log` doesn't show
+/// the full paths of files brought in with `git subtree`, so their
+/// histories stop where they were merged in.
+/// (`git log --follow <path>` on each unresolved path can fill in the rest.)
+pub fn gather_history_report<'a, T, V, F, I>(paths: &'a PathSet, v: V, f: F,
+                                             options: GatherOptions<'a, T>,
+                                             commits: I)
+    -> Result<Gathered<T>, GatherError>
+    where V: Visitor<T>, F: Fn(&NodeContext) -> bool,
+          I: IntoIterator<Item = ParsedCommit> {
+    let mut state = HistoryState::new(paths, v, f, options, RcNodes);
+    state.consume(commits)?;
+
+    let mut unresolved: Vec<_> = state.pending_edges.keys()
+        .map(|&p| state.paths.path(p).clone())
+        .collect();
+    unresolved.sort();
+    Ok(Gathered{ history: state.history, unresolved })
+}
+
+/// Lists the commits that changed each of the given files in the repo
+/// at (or containing) `repo`, newest first, along with when they were made
+///
+/// This is `git log --follow --format=%H` for every path at once - the
+/// quickest way to get going if you don't need a visitor of your own.
+pub fn commit_log(paths: &PathSet, repo: &Path)
+    -> Result<HashMap<GitPath, Vec<(SHA1, SystemTime)>>, ParseError> {
+    let mut error = None;
+    let commits = CommitIter::new(LogOptions::new().repo(Some(repo.to_path_buf())))?
+        .map_while(|c| c.map_err(|e| error = Some(e)).ok());
+
+    let tree = gather_history_iter(paths, |_: &NodeContext| (), |_: &NodeContext| true, commits);
+    if let Some(e) = error {
+        return Err(e);
     }
-    */
 
+    Ok(tree.iter()
+        .map(|(path, head)| {
+            let log = BranchIter::new(head)
+                .map(|n| (n.borrow().id, n.borrow().when))
+                .collect();
+            (path.clone(), log)
+        })
+        .collect())
+}
+
+/// Extends a tree built by an earlier call to `gather_history()` with newer
+/// commits, instead of walking the whole history again
+///
+/// `commit_source` should provide only the commits since `previous` was built
+/// (e.g., `oldHead..newHead`), and `paths` should be the set of files we care
+/// about as of the newest commit. The result should match what
+/// `gather_history()` would have built from the full history:
+/// new nodes are linked above the old heads, and branches are re-keyed
+/// if their files were renamed in the new commits.
+pub fn gather_history_incremental<T, V, F>(previous: &HistoryTree<T>,
+                                           paths: &PathSet, v: V, f: F,
+                                           commit_source: &Receiver<ParsedCommit>)
+    -> HistoryTree<T>
+    where V: Visitor<T>, F: Fn(&NodeContext) -> bool {
+    let mut state = HistoryState::new(paths, v, f, GatherOptions::default(), RcNodes);
+    state.consume(commit_source.iter()).expect("Gathering with no limits shouldn't fail");
+    state.graft_onto(previous);
     state.history
 }
+
+/// Like `gather_history_iter_with()`, but builds a `HistoryArena`
+/// instead of a tree
+///
+/// The arena is built as we go, without the `Rc` and `RefCell` for each node,
+/// so this is lighter on memory than gathering a tree and calling
+/// `to_arena()` - and `T` doesn't have to be `Clone`.
+/// `GatherOptions::on_branch_start()` isn't called, since there's no `Link`
+/// to give it.
+pub fn gather_history_arena<'a, T, V, F, I>(paths: &'a PathSet, v: V, f: F,
+                                            options: GatherOptions<'a, T>,
+                                            commits: I)
+    -> Result<HistoryArena<T>, GatherError>
+    where V: Visitor<T>, F: Fn(&NodeContext) -> bool,
+          I: IntoIterator<Item = ParsedCommit> {
+    let mut state = HistoryState::new(paths, v, f, options, Vec::new());
+    state.consume(commits)?;
+    Ok(HistoryArena{ nodes: state.nodes, heads: state.history })
+}
+
+/// Returns a key identifying the given node, for use with maps like the one
+/// returned by `child_map()`
+pub fn node_ptr<T>(node: &Link<HistoryNode<T>>) -> *const () {
+    Rc::as_ptr(node) as *const ()
+}
+
+/// Counts the immediate children of every node in the tree
+///
+/// Nodes only link to their previous changes, so this is the only way to see
+/// where branches converge (e.g., a file that was copied elsewhere).
+/// Nodes are keyed by `node_ptr()`, and nodes with no children map to zero.
+pub fn child_map<T>(tree: &HistoryTree<T>) -> HashMap<*const (), usize> {
+    let mut children = HashMap::new();
+
+    for_each_node(tree, |node| {
+        // Heads of branches can be children too (see above),
+        // so don't clobber a count we've already made.
+        children.entry(node_ptr(node)).or_insert(0);
+
+        if let Some(ref prev) = node.borrow().previous {
+            *children.entry(node_ptr(prev)).or_insert(0) += 1;
+        }
+    });
+
+    children
+}
+
+/// Returns the IDs of all commits that made a change in the tree
+///
+/// This is a good starting point for gathering more info about those commits
+/// in bulk, rather than asking Git about each one as we come across it.
+pub fn referenced_commits<T>(tree: &HistoryTree<T>) -> HashSet<SHA1> {
+    let mut commits = HashSet::new();
+    for_each_node(tree, |node| { commits.insert(node.borrow().id); });
+    commits
+}
+
+/// Calls `f` once for each node in the tree, even those shared by several
+/// branches
+fn for_each_node<T, F>(tree: &HistoryTree<T>, mut f: F)
+    where F: FnMut(&Link<HistoryNode<T>>) {
+    let mut visited = HashSet::new();
+
+    for head in tree.values() {
+        let mut current = head.clone();
+        loop {
+            // If we've been here before, we've seen everything past it too.
+            if !visited.insert(node_ptr(&current)) { break; }
+
+            f(&current);
+
+            let prev = match current.borrow().previous {
+                Some(ref p) => p.clone(),
+                None => break
+            };
+            current = prev;
+        }
+    }
+}
+
+/// Walks a branch from its head to the start of its history,
+/// following each node's `previous` change
+///
+/// This loops instead of recursing, so it's fine on histories of any length.
+pub struct BranchIter<T> {
+    current: Option<Link<HistoryNode<T>>>,
+}
+
+impl<T> BranchIter<T> {
+    /// Starts at `head`, which is the first node returned.
+    pub fn new(head: &Link<HistoryNode<T>>) -> BranchIter<T> {
+        BranchIter{ current: Some(head.clone()) }
+    }
+}
+
+impl<T> Iterator for BranchIter<T> {
+    type Item = Link<HistoryNode<T>>;
+
+    fn next(&mut self) -> Option<Link<HistoryNode<T>>> {
+        let node = self.current.take()?;
+        self.current = node.borrow().previous.clone();
+        Some(node)
+    }
+}
+
+/// Walks forward in time from a node to the newest change to its file,
+/// following each node's `HistoryNode::next_change()`
+///
+/// This is `BranchIter` in reverse, and handy for finding where a file
+/// from some old commit ended up after it was renamed.
+pub struct ForwardIter<T> {
+    current: Option<Link<HistoryNode<T>>>,
+}
+
+impl<T> ForwardIter<T> {
+    /// Starts at `node`, which is the first node returned.
+    pub fn new(node: &Link<HistoryNode<T>>) -> ForwardIter<T> {
+        ForwardIter{ current: Some(node.clone()) }
+    }
+}
+
+impl<T> Iterator for ForwardIter<T> {
+    type Item = Link<HistoryNode<T>>;
+
+    fn next(&mut self) -> Option<Link<HistoryNode<T>>> {
+        let node = self.current.take()?;
+        self.current = node.borrow().next_change();
+        Some(node)
+    }
+}
+
+/// Walks the branch for `path` in the tree, if there is one
+/// (see `BranchIter`)
+pub fn branch<T>(tree: &HistoryTree<T>, path: &GitPathRef) -> Option<BranchIter<T>> {
+    tree.get(path).map(BranchIter::new)
+}
+
+/// Counts the changes in the branch for `path` (following renames and copies),
+/// or returns zero if there's no such branch
+///
+/// The branch's newest change is just `tree.get(path)`.
+pub fn branch_len<T>(tree: &HistoryTree<T>, path: &GitPathRef) -> usize {
+    branch(tree, path).map_or(0, Iterator::count)
+}
+
+/// Returns the oldest change in the branch for `path` - usually where the file
+/// (or whatever it was copied or renamed from) was added
+pub fn oldest_change<T>(tree: &HistoryTree<T>, path: &GitPathRef)
+    -> Option<Link<HistoryNode<T>>> {
+    tree.get(path).map(oldest_node)
+}
+
+/// Collects a branch's nodes into a list, newest first
+///
+/// Nodes with no data (see `HistoryNode::data`) are included,
+/// so the list is the whole chain - skip them yourself if you like.
+pub fn flatten_branch<T>(head: &Link<HistoryNode<T>>) -> Vec<Link<HistoryNode<T>>> {
+    BranchIter::new(head).collect()
+}
+
+/// Lists each branch's changes oldest first, for walking the history
+/// forward in time
+///
+/// The keys are the same as the tree's: each file's path as of its newest
+/// change. The first node in each list is where the file (or what it was
+/// copied or renamed from) was added, and the last is the tree's head.
+///
+/// The tree's links aren't flipped around to do this, since a node that was
+/// copied has several newer changes, and `previous` only holds one.
+/// See `HistoryNode::next_changes()` to step forward from a particular node.
+pub fn oldest_first<T>(tree: &HistoryTree<T>) -> HashMap<GitPath, Vec<Link<HistoryNode<T>>>> {
+    tree.iter()
+        .map(|(path, head)| {
+            let mut changes = flatten_branch(head);
+            changes.reverse();
+            (path.clone(), changes)
+        })
+        .collect()
+}
+
+/// Copies the tree into a `HistoryArena`, which can be sent to other threads
+///
+/// Nodes are numbered branch by branch, in order of path, newest first,
+/// so the same tree always makes the same arena.
+/// Nodes that aren't in any branch are left out.
+pub fn to_arena<T: Clone>(tree: &HistoryTree<T>) -> HistoryArena<T> {
+    let mut paths: Vec<_> = tree.keys().collect();
+    paths.sort();
+
+    let mut ids = HashMap::new();
+    let mut links = Vec::new();
+    let mut arena = HistoryArena{ nodes: Vec::new(), heads: HashMap::new() };
+
+    for path in paths {
+        for node in BranchIter::new(&tree[path]) {
+            // Branches can share history, so stop once we're in familiar territory.
+            if ids.contains_key(&node_ptr(&node)) { break; }
+
+            ids.insert(node_ptr(&node), NodeId(arena.nodes.len()));
+            let nb = node.borrow();
+            arena.nodes.push(ArenaNode{ node: NodeData::from_node(&nb),
+                                        previous: None,
+                                        next: Vec::new(),
+                                        cochanged: nb.cochanged.clone() });
+            links.push(node.clone());
+        }
+        arena.heads.insert(path.clone(), ids[&node_ptr(&tree[path])]);
+    }
+
+    // Now that everything has an ID, we can link them up.
+    for (an, node) in arena.nodes.iter_mut().zip(links) {
+        let nb = node.borrow();
+        an.previous = nb.previous.as_ref().map(|p| ids[&node_ptr(p)]);
+        an.next = nb.next_changes().iter()
+            .filter_map(|n| ids.get(&node_ptr(n)).cloned())
+            .collect();
+    }
+
+    arena
+}
+
+/// Removes branches with fewer than `min_changes` nodes
+/// (counting through renames and copies) from the tree
+///
+/// Only the branches' entries in the tree are removed, so any history they
+/// share with other branches (e.g. the source of a copy) is left intact.
+pub fn prune_short<T>(tree: &mut HistoryTree<T>, min_changes: usize) {
+    tree.retain(|_, head| chain_length(head, min_changes) >= min_changes);
+}
+
+/// Removes nodes with no data (see `HistoryNode::data`) from the tree,
+/// linking each remaining node to the next older one that has data
+///
+/// A branch whose head has no data starts at its first node that does,
+/// and branches with no data at all are removed.
+pub fn prune_empty<T>(tree: &mut HistoryTree<T>) {
+    let mut emptied = Vec::new();
+    for (path, head) in tree.iter_mut() {
+        match first_with_data(Some(head.clone())) {
+            Some(h) => { *head = h; }
+            None => { emptied.push(path.clone()); }
+        }
+    }
+    for path in emptied {
+        tree.remove(&path);
+    }
+
+    // Branches can share history, so only splice each chain once.
+    let mut visited = HashSet::new();
+    for head in tree.values() {
+        let mut current = head.clone();
+        while visited.insert(node_ptr(&current)) {
+            let next = first_with_data(current.borrow().previous.clone());
+            set_previous(&current, next.clone());
+            current = match next {
+                Some(n) => n,
+                None => break
+            };
+
+            // Forget the nodes we spliced out on the way here.
+            current.borrow_mut().next.retain(|w| {
+                w.upgrade().is_some_and(|n| n.borrow().data.is_some())
+            });
+        }
+    }
+}
+
+/// Returns the first node with data in the chain starting at `node`
+/// (which is included)
+fn first_with_data<T>(node: Option<Link<HistoryNode<T>>>) -> Option<Link<HistoryNode<T>>> {
+    node.and_then(|n| BranchIter::new(&n).find(|n| n.borrow().data.is_some()))
+}
+
+/// Counts the nodes from `head` to the start of its history,
+/// stopping early once we hit `limit`
+fn chain_length<T>(head: &Link<HistoryNode<T>>, limit: usize) -> usize {
+    let mut len = 1;
+    let mut current = head.clone();
+
+    while len < limit {
+        let prev = match current.borrow().previous {
+            Some(ref p) => p.clone(),
+            None => break
+        };
+        len += 1;
+        current = prev;
+    }
+
+    len
+}
+
+/// Merges trees built from several repositories into one, keyed by path
+///
+/// This is for following files across repo boundaries,
+/// e.g., when code was moved from one repo to another.
+/// Trees should be given newest repo first. Linking across repos is entirely
+/// up to `correlate`: for each branch's oldest node so far, it's asked about
+/// the heads of the next tree, and the first one it matches (say, by having
+/// the same blob) becomes that node's previous change. Nothing is linked
+/// automatically. Heads are offered in path order, so if `correlate` matches
+/// several, the one with the lowest path wins.
+///
+/// Heads that get linked are left out of the result, since they're now part
+/// of a newer branch. Of the rest, if several trees have a branch at the same
+/// path, the one from the earliest tree wins.
+pub fn combine<T, I, C>(trees: I, mut correlate: C) -> HistoryTree<T>
+    where I: IntoIterator<Item = HistoryTree<T>>,
+          C: FnMut(&HistoryNode<T>, &HistoryNode<T>) -> bool
+{
+    let mut combined = HistoryTree::new();
+
+    for tree in trees {
+        // Find each distinct root first, so that once we link one,
+        // we don't go looking for roots inside the tree we just linked in.
+        let mut seen = HashSet::new();
+        let roots: Vec<_> = combined.values()
+            .map(oldest_node)
+            .filter(|root| seen.insert(node_ptr(root)))
+            .collect();
+
+        let mut linked = HashSet::new();
+        {
+            // HashMap order changes from run to run, so sort the heads
+            // to break ties the same way every time.
+            let mut heads: Vec<_> = tree.iter().collect();
+            heads.sort_by(|a, b| a.0.cmp(b.0));
+
+            for root in roots {
+                let matched = heads.iter()
+                    .find(|&&(_, head)| correlate(&root.borrow(), &head.borrow()));
+
+                if let Some(&(path, head)) = matched {
+                    set_previous(&root, Some(head.clone()));
+                    linked.insert(path.clone());
+                }
+            }
+        }
+
+        for (path, head) in tree {
+            if !linked.contains(&path) {
+                combined.entry(path).or_insert(head);
+            }
+        }
+    }
+
+    combined
+}
+
+/// Counts the changes each author made to a file over its whole history
+/// (following renames and copies), starting at `head`
+///
+/// Nodes only know who made them if the log included identities
+/// (see `parsing::LogOptions::identities()`), so nodes without an author
+/// aren't counted. Neither are nodes your filter turned down
+/// (see `HistoryNode::data`), since those changes weren't interesting.
+/// A file with only one author is a classic maintenance risk.
+pub fn author_diversity<T>(head: &Link<HistoryNode<T>>) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+
+    for node in BranchIter::new(head) {
+        let node = node.borrow();
+        if let (Some(_), Some(author)) = (&node.data, &node.author) {
+            *counts.entry(author.name.clone()).or_insert(0) += 1;
+        }
+    }
+
+    counts
+}
+
+/// Collapses the tree into a list of changes for each path, newest first
+///
+/// Each list follows the file's history through renames and copies,
+/// so the changes under its old names are at the end.
+/// History shared by several branches (e.g., the source of a copy)
+/// is copied into each of their lists.
+pub fn flatten<T: Clone>(tree: &HistoryTree<T>) -> HashMap<GitPath, Vec<NodeData<T>>> {
+    tree.iter()
+        .map(|(path, head)| {
+            let changes = BranchIter::new(head)
+                .map(|node| NodeData::from_node(&node.borrow()))
+                .collect();
+            (path.clone(), changes)
+        })
+        .collect()
+}
+
+/// How two trees differ (see `diff_trees()`)
+///
+/// Each list is sorted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeDiff {
+    /// Paths with a branch only in the new tree
+    pub added: Vec<GitPath>,
+    /// Paths with a branch only in the old tree
+    pub removed: Vec<GitPath>,
+    /// Paths in both trees whose branches differ - a new head,
+    /// gained or lost changes, or different data somewhere along the way
+    pub changed: Vec<GitPath>,
+}
+
+impl TreeDiff {
+    /// Returns true if the trees had the same branches
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compares two trees, e.g., to check whether a change to the repo
+/// (or to this library) changed the analysis
+///
+/// Branches are compared node by node, by commit, path, change, and data.
+pub fn diff_trees<T: PartialEq>(old: &HistoryTree<T>, new: &HistoryTree<T>) -> TreeDiff {
+    let mut diff = TreeDiff::default();
+
+    for (path, new_head) in new {
+        match old.get(path) {
+            None => diff.added.push(path.clone()),
+            Some(old_head) if !same_branch(old_head, new_head) => {
+                diff.changed.push(path.clone())
+            }
+            Some(_) => { }
+        }
+    }
+    diff.removed = old.keys().filter(|p| !new.contains_key(*p)).cloned().collect();
+
+    diff.added.sort();
+    diff.removed.sort();
+    diff.changed.sort();
+    diff
+}
+
+/// Returns true if the branches starting at `a` and `b` have the same changes
+fn same_branch<T: PartialEq>(a: &Link<HistoryNode<T>>, b: &Link<HistoryNode<T>>) -> bool {
+    let mut a = Some(a.clone());
+    let mut b = Some(b.clone());
+
+    loop {
+        let (x, y) = match (a, b) {
+            (None, None) => return true,
+            (Some(x), Some(y)) => (x, y),
+            _ => return false
+        };
+
+        // Shared history is the same by definition.
+        if Rc::ptr_eq(&x, &y) { return true; }
+
+        let (x, y) = (x.borrow(), y.borrow());
+        if x.id != y.id || x.path != y.path || x.change != y.change || x.data != y.data {
+            return false;
+        }
+        a = x.previous.clone();
+        b = y.previous.clone();
+    }
+}
+
+/// Follows `head` back to the first change in its history
+fn oldest_node<T>(head: &Link<HistoryNode<T>>) -> Link<HistoryNode<T>> {
+    let mut current = head.clone();
+    loop {
+        let prev = match current.borrow().previous {
+            Some(ref p) => p.clone(),
+            None => break
+        };
+        current = prev;
+    }
+    current
+}
+
+/// Splits the tree's branches up by directory, to the given depth
+///
+/// Each branch is keyed by its path's first `depth` directories, e.g.,
+/// `src/net/tcp.rs` is under `src` for a depth of 1 and `src/net` for
+/// a depth of 2 or more. Files closer to the top than `depth` are grouped
+/// with their immediate directory, so top-level files are under the empty path.
+pub fn group_by_dir<T>(tree: &HistoryTree<T>, depth: usize)
+    -> HashMap<GitPath, Vec<Branch<T>>> {
+    let mut groups : HashMap<GitPath, Vec<_>> = HashMap::new();
+
+    for (path, head) in tree {
+        groups.entry(dir_prefix(path, depth))
+            .or_default()
+            .push((path.clone(), head.clone()));
+    }
+
+    groups
+}
+
+/// Returns the first `depth` directories of `path` (see `group_by_dir()`)
+fn dir_prefix(path: &GitPathRef, depth: usize) -> GitPath {
+    let bytes = path_bytes(path);
+    let mut slashes = bytes.iter().enumerate()
+        .filter(|&(_, &b)| b == b'/')
+        .map(|(i, _)| i);
+
+    let end = if depth == 0 {
+            0
+        }
+        else {
+            // The last slash separates the file name,
+            // which is never part of the prefix.
+            match slashes.nth(depth - 1) {
+                Some(i) => i,
+                None => bytes.iter().rposition(|&b| b == b'/').unwrap_or(0)
+            }
+        };
+    path_from_bytes(bytes[..end].to_vec())
+}
+
+/// A way in which a `HistoryTree` is malformed (see `validate()`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvariantViolation {
+    /// A node's previous change is newer than it is.
+    /// The nodes are given by their `HistoryNode::stable_id()`.
+    TimeTravel{ node: String, previous: String },
+
+    /// Following the previous changes from the named branch's head
+    /// leads back around to a node we've already seen.
+    Cycle{ branch: GitPath },
+
+    /// The named branch isn't in the set of paths we asked for.
+    UntrackedBranch{ branch: GitPath },
+}
+
+/// Checks that the tree is well-formed, returning every problem found
+///
+/// Specifically, it checks that:
+///
+/// 1. Every node's previous change (if any) is as old or older than it is.
+///    Note that Git doesn't actually promise this - commits' timestamps come
+///    from whoever made them - so rebased or imported history can trip it
+///    without anything being wrong with the tree.
+///
+/// 2. No node is its own ancestor, since following a branch should eventually
+///    end (and since `Rc` cycles leak).
+///
+/// 3. Every branch in the tree is for a path in `paths`
+///    (or under a directory in it), the set the tree was built from.
+pub fn validate<T>(tree: &HistoryTree<T>, paths: &PathSet)
+    -> Result<(), Vec<InvariantViolation>> {
+    let mut violations = Vec::new();
+
+    // Nodes we've already walked past without finding a cycle
+    let mut checked = HashSet::new();
+
+    for (branch, head) in tree {
+        if !path_set_tracks(paths, branch) {
+            violations.push(InvariantViolation::UntrackedBranch{ branch: branch.clone() });
+        }
+
+        // Nodes we've seen while walking this branch
+        let mut on_branch = HashSet::new();
+
+        let mut current = head.clone();
+        loop {
+            let ptr = node_ptr(&current);
+            if checked.contains(&ptr) { break; }
+            if !on_branch.insert(ptr) {
+                violations.push(InvariantViolation::Cycle{ branch: branch.clone() });
+                break;
+            }
+
+            let prev = match current.borrow().previous {
+                Some(ref p) => p.clone(),
+                None => break
+            };
+
+            if prev.borrow().when > current.borrow().when {
+                violations.push(InvariantViolation::TimeTravel{
+                    node: current.borrow().stable_id(),
+                    previous: prev.borrow().stable_id()
+                });
+            }
+
+            current = prev;
+        }
+
+        checked.extend(on_branch);
+    }
+
+    if violations.is_empty() { Ok(()) } else { Err(violations) }
+}
+
+/// Renders the tree as a [Mermaid](https://mermaid.js.org/) flowchart,
+/// which GitHub, GitLab, and friends will draw in Markdown.
+///
+/// Each node is labeled with its data, or its commit ID if it has none
+/// (see `HistoryNode::data`), and points at its previous change.
+/// Each branch's path is a box pointing at the branch's head.
+/// Nodes shared by several branches only appear once.
+pub fn to_mermaid<T: Display>(tree: &HistoryTree<T>) -> String {
+    let mut out = String::from("graph TD\n");
+
+    // Mermaid wants simple IDs, so number the nodes as we come across them.
+    let mut ids = HashMap::new();
+    let mut printed = HashSet::new();
+
+    // Sort the branches so we get the same output every time.
+    let mut branches : Vec<_> = tree.iter().collect();
+    branches.sort_by(|a, b| a.0.cmp(b.0));
+
+    for (i, (path, head)) in branches.into_iter().enumerate() {
+        let _ = writeln!(out, "    b{}[[\"{}\"]]", i, mermaid_escape(&display_path(path)));
+        let _ = writeln!(out, "    b{} --> n{}", i, node_number(&mut ids, head));
+
+        let mut current = head.clone();
+        // If we've printed a node already, we've printed everything past it.
+        while printed.insert(node_ptr(&current)) {
+            let id = node_number(&mut ids, &current);
+            let prev = {
+                let node = current.borrow();
+                let label = match node.data {
+                    Some(ref d) => d.to_string(),
+                    None => node.id.to_string()
+                };
+                let _ = writeln!(out, "    n{}[\"{}\"]", id, mermaid_escape(&label));
+                node.previous.clone()
+            };
+
+            match prev {
+                Some(p) => {
+                    let _ = writeln!(out, "    n{} --> n{}", id, node_number(&mut ids, &p));
+                    current = p;
+                }
+                None => break
+            }
+        }
+    }
+
+    out
+}
+
+/// Renders the tree as a [Graphviz](https://graphviz.org/) `digraph`,
+/// which is handy for eyeballing how the nodes got linked
+///
+/// Each node is labeled with its abbreviated commit ID, its change,
+/// and its data (if it has any), and points at its previous change.
+/// Like `to_mermaid()`, each branch's path points at its head, and nodes
+/// shared by several branches only appear once - with several edges
+/// coming in.
+pub fn to_dot<T: Display>(tree: &HistoryTree<T>) -> String {
+    let mut out = String::from("digraph history {\n");
+
+    let mut ids = HashMap::new();
+    let mut printed = HashSet::new();
+
+    let mut branches : Vec<_> = tree.iter().collect();
+    branches.sort_by(|a, b| a.0.cmp(b.0));
+
+    for (i, (path, head)) in branches.into_iter().enumerate() {
+        let _ = writeln!(out, "    b{} [shape=box, label={}];",
+                         i, dot_string(&display_path(path)));
+        let _ = writeln!(out, "    b{} -> n{};", i, node_number(&mut ids, head));
+
+        let mut current = head.clone();
+        while printed.insert(node_ptr(&current)) {
+            let id = node_number(&mut ids, &current);
+            let prev = {
+                let node = current.borrow();
+                let mut label = format!("{} {:?}", &node.id.to_string()[..7], node.change);
+                if let Some(ref d) = node.data {
+                    let _ = write!(label, "\n{}", d);
+                }
+                let _ = writeln!(out, "    n{} [label={}];", id, dot_string(&label));
+                node.previous.clone()
+            };
+
+            match prev {
+                Some(p) => {
+                    let _ = writeln!(out, "    n{} -> n{};", id, node_number(&mut ids, &p));
+                    current = p;
+                }
+                None => break
+            }
+        }
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+/// Quotes and escapes a label for Graphviz
+fn dot_string(label: &str) -> String {
+    format!("\"{}\"", label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+/// Returns the ID number for the given node, assigning one if needed
+/// (for `to_mermaid()` and `to_dot()`)
+fn node_number<T>(ids: &mut HashMap<*const (), usize>, node: &Link<HistoryNode<T>>) -> usize {
+    let next = ids.len();
+    *ids.entry(node_ptr(node)).or_insert(next)
+}
+
+/// Escapes a label for use in a quoted Mermaid node
+fn mermaid_escape(label: &str) -> String {
+    label.replace('"', "#quot;").replace('\n', " ")
+}
+
+/// Flattens the tree (see `flatten()`) for serializing with the `serde`
+/// feature, say, to feed the results to a web frontend
+///
+/// The `Rc` graph becomes plain nested data: each branch's changes, newest
+/// first, keyed by the branch's path (see `display_path()`) and sorted.
+/// With `serde_json`, that looks like
+///
+/// ```text
+/// { "src/foo.rs": [
+///     { "data": ..., "id": "<SHA1>", "when": <Unix time>, "path": "src/foo.rs",
+///       "change": { "kind": "Renamed", "similarity": 93 }, "blob": null },
+///     ... ],
+///   ... }
+/// ```
+///
+/// Nodes with no data get `null` for it. With `bytes-paths`, each node's
+/// `path` is serialized as its bytes, so nothing is lost.
+#[cfg(feature = "serde")]
+pub fn serializable<T: Clone>(tree: &HistoryTree<T>) -> BTreeMap<String, Vec<NodeData<T>>> {
+    flatten(tree).into_iter()
+        .map(|(path, changes)| (display_path(&path).into_owned(), changes))
+        .collect()
+}
+
+/// Fetches the file's contents as of the given node (see `content_at()`)
+///
+/// Deletions have no contents at their own commit, so fetching a `Deleted`
+/// node's contents returns an error, as does any other Git failure.
+pub fn node_blob<T>(repo: &Path, node: &HistoryNode<T>) -> io::Result<Vec<u8>> {
+    content_at(repo, node.id, &node.path)
+}
+
+/// Fetches the contents of `path` as of commit `rev` in `repo`
+/// (from `git cat-file blob <rev>:<path>`)
+///
+/// We use `cat-file` instead of `git show` since the latter can run
+/// the repo's textconv filters - we want exactly what Git stored.
+/// `SHA1::NULL` (see `parsing::LogOptions::working_tree()`) reads the file
+/// from the working tree instead.
+pub fn content_at(repo: &Path, rev: SHA1, path: &GitPathRef) -> io::Result<Vec<u8>> {
+    let git = |args: &[&OsStr]| -> io::Result<Vec<u8>> {
+        let output = Command::new(git_program())
+            .arg("-C").arg(repo)
+            .args(args)
+            .output()?;
+
+        if output.status.success() {
+            Ok(output.stdout)
+        }
+        else {
+            Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()))
+        }
+    };
+
+    if rev == SHA1::NULL {
+        let top = git(&[OsStr::new("rev-parse"), OsStr::new("--show-toplevel")])?;
+        let top = String::from_utf8_lossy(&top).trim_end().to_string();
+        return fs::read(Path::new(&top).join(path_to_os(path)?));
+    }
+
+    let mut spec = OsString::from(format!("{}:", rev));
+    spec.push(path_to_os(path)?);
+    git(&[OsStr::new("cat-file"), OsStr::new("blob"), &spec])
+}
+
+/// A `Visitor` that gives each node the file's contents as of that change
+/// (see `content_at()`)
+///
+/// A deletion's node gets `None`, since there's nothing left at that commit.
+/// (The contents before it are on the node's `previous`.)
+/// This runs Git once per node, so consider being picky with your filter.
+pub struct ContentVisitor {
+    repo: PathBuf,
+}
+
+impl ContentVisitor {
+    /// Fetches contents from the repo at (or containing) `repo`
+    pub fn new(repo: PathBuf) -> ContentVisitor { ContentVisitor{ repo } }
+}
+
+impl Visitor<io::Result<Option<Vec<u8>>>> for ContentVisitor {
+    fn visit(&self, ctx: &NodeContext) -> io::Result<Option<Vec<u8>>> {
+        if ctx.change == Change::Deleted {
+            return Ok(None);
+        }
+        content_at(&self.repo, ctx.commit.id, ctx.path).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use parsing::MergeDiffs;
+    use testing::*;
+
+    /// Tags each node with its commit's ID
+    fn commit_id(ctx: &NodeContext) -> SHA1 { ctx.commit.id }
+
+    fn everything(_: &NodeContext) -> bool { true }
+
+    /// Parses `log` and gathers the history of `paths` from it
+    fn gather(log: &Log, paths: &[&str]) -> HistoryTree<SHA1> {
+        let commits = log.parse(&LogOptions::new()).unwrap();
+        gather_history_iter(&path_set(paths), commit_id, everything, commits)
+    }
+
+    /// Parses `log` with `log_options` and gathers the history of `paths`
+    /// with `options`
+    fn gather_with(log: &Log, log_options: &LogOptions, paths: &[&str],
+                   options: GatherOptions<SHA1>) -> HistoryTree<SHA1> {
+        let commits = log.parse(log_options).unwrap();
+        gather_history_iter_with(&path_set(paths), commit_id, everything, options, commits)
+            .unwrap()
+    }
+
+    /// Returns the commit of each change in the branch for `p`, newest first
+    fn branch_ids(tree: &HistoryTree<SHA1>, p: &str) -> Vec<SHA1> {
+        BranchIter::new(&tree[&path(p)]).map(|n| n.borrow().id).collect()
+    }
+
+    /// A file renamed from `a` to `b`, and another added alongside it
+    fn renamed_log() -> Log {
+        let mut log = Log::new();
+        log.commit(3, &[2], &["R090", "a", "b"])
+            .commit(2, &[1], &["M", "a", "A", "c"])
+            .commit(1, &[], &["A", "a"]);
+        log
+    }
+
+    #[test]
+    fn flattens_branches_through_renames() {
+        let flat = flatten(&gather(&renamed_log(), &["b", "c"]));
+        let b: Vec<_> = flat[&path("b")].iter().map(|n| (n.id, n.path.clone())).collect();
+        assert_eq!(b, [(sha(3), path("b")), (sha(2), path("a")), (sha(1), path("a"))]);
+        assert_eq!(flat[&path("c")].len(), 1);
+        assert_eq!(flat[&path("c")][0].data, Some(sha(2)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serializes_flattened_branches() {
+        let tree = gather(&renamed_log(), &["b", "c"]);
+        let json = serde_json::to_string(&serializable(&tree)).unwrap();
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let b = &value["b"];
+        assert_eq!(b.as_array().unwrap().len(), 3);
+        assert_eq!(b[0]["id"], sha(3).to_string());
+        assert_eq!(b[0]["data"], sha(3).to_string());
+        assert_eq!(b[0]["when"], when(3));
+        assert_eq!(b[0]["path"], serde_json::to_value(path("b")).unwrap());
+        assert_eq!(b[0]["change"], serde_json::json!({ "kind": "Renamed", "similarity": 90 }));
+        assert_eq!(b[2]["path"], serde_json::to_value(path("a")).unwrap());
+        assert_eq!(b[2]["change"], serde_json::json!({ "kind": "Added" }));
+        assert_eq!(value["c"][0]["blob"], serde_json::Value::Null);
+
+        let back: BTreeMap<String, Vec<NodeData<SHA1>>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, serializable(&tree));
+    }
+
+    /// A text file and a binary one changed in the same commits,
+    /// with `--raw --numstat`
+    fn binary_log() -> Log {
+        let mut log = Log::new();
+        log.commit(3, &[2], &[&raw(11, 12, "M") as &str, "img.png", &raw(21, 22, "M"), "a.txt",
+                              "-\t-\timg.png", "1\t1\ta.txt"])
+            .commit(2, &[1], &[&raw(10, 11, "R090") as &str, "old.png", "img.png",
+                              &raw(20, 21, "M"), "a.txt",
+                              "-\t-\t", "old.png", "img.png", "2\t0\ta.txt"])
+            .commit(1, &[], &[&raw(0, 10, "A") as &str, "old.png", &raw(0, 20, "A"), "a.txt",
+                             "-\t-\told.png", "1\t0\ta.txt"]);
+        log
+    }
+
+    #[test]
+    fn skips_binary_files() {
+        let mut log_options = LogOptions::new();
+        log_options.numstat(true);
+
+        let commits = binary_log().parse(&log_options).unwrap();
+        let binary: Vec<_> = commits[0].deltas.iter().map(FileDelta::is_binary).collect();
+        assert_eq!(binary, [Some(true), Some(false)]);
+
+        let tree = gather_with(&binary_log(), &log_options, &["img.png", "a.txt"],
+                               GatherOptions::new());
+        assert_eq!(branch_ids(&tree, "img.png"), [sha(3), sha(2), sha(1)]);
+
+        let mut options = GatherOptions::new();
+        options.skip_binary(true);
+        let tree = gather_with(&binary_log(), &log_options, &["img.png", "a.txt"], options);
+        assert!(!tree.contains_key(&path("img.png")));
+        assert_eq!(branch_ids(&tree, "a.txt"), [sha(3), sha(2), sha(1)]);
+    }
+
+    #[test]
+    fn doesnt_know_whats_binary_without_numstat() {
+        let commits = Log::new().commit(1, &[], &["A", "img.png"])
+            .parse(&LogOptions::new()).unwrap();
+        assert_eq!(commits[0].deltas[0].is_binary(), None);
+    }
+
+    #[test]
+    fn incremental_builds_match_full_ones() {
+        // Two new commits (renaming a to b) on top of three old ones
+        let newer = |log: &mut Log| {
+            log.commit(5, &[4], &["R080", "a", "b", "M", "c"])
+                .commit(4, &[3], &["M", "a"]);
+        };
+        let older = |log: &mut Log| {
+            log.commit(3, &[2], &["M", "a", "M", "c"])
+                .commit(2, &[1], &["A", "c"])
+                .commit(1, &[], &["A", "a"]);
+        };
+        let (mut full, mut new, mut old) = (Log::new(), Log::new(), Log::new());
+        newer(&mut full);
+        older(&mut full);
+        newer(&mut new);
+        older(&mut old);
+
+        let full = gather(&full, &["b", "c"]);
+        let previous = gather(&old, &["a", "c"]);
+
+        let (tx, rx) = mpsc::sync_channel(16);
+        for commit in new.parse(&LogOptions::new()).unwrap() {
+            tx.send(commit).unwrap();
+        }
+        drop(tx);
+        let incremental = gather_history_incremental(&previous, &path_set(&["b", "c"]),
+                                                     commit_id, everything, &rx);
+
+        assert_eq!(flatten(&incremental), flatten(&full));
+        assert_eq!(branch_ids(&incremental, "b"), [sha(5), sha(4), sha(3), sha(1)]);
+        assert!(diff_trees(&full, &incremental).is_empty());
+    }
+
+    #[test]
+    fn follows_resurrections_of_identical_contents() {
+        // a is deleted, then brought back as it was.
+        let mut log = Log::new();
+        log.commit(4, &[3], &[&raw(11, 12, "M") as &str, "a"])
+            .commit(3, &[2], &[&raw(0, 11, "A") as &str, "a"])
+            .commit(2, &[1], &[&raw(11, 0, "D") as &str, "a"])
+            .commit(1, &[], &[&raw(0, 11, "A") as &str, "a"]);
+        let mut log_options = LogOptions::new();
+        log_options.blobs(true);
+
+        let tree = gather_with(&log, &log_options, &["a"], GatherOptions::new());
+        assert_eq!(branch_ids(&tree, "a"), [sha(4), sha(3)]);
+
+        let mut options = GatherOptions::new();
+        options.follow_resurrections(true);
+        let tree = gather_with(&log, &log_options, &["a"], options);
+        assert_eq!(branch_ids(&tree, "a"), [sha(4), sha(3), sha(2), sha(1)]);
+    }
+
+    #[test]
+    fn doesnt_resurrect_different_contents() {
+        let mut log = Log::new();
+        log.commit(3, &[2], &[&raw(0, 12, "A") as &str, "a"])
+            .commit(2, &[1], &[&raw(11, 0, "D") as &str, "a"])
+            .commit(1, &[], &[&raw(0, 11, "A") as &str, "a"]);
+        let mut log_options = LogOptions::new();
+        log_options.blobs(true);
+
+        let mut options = GatherOptions::new();
+        options.follow_resurrections(true);
+        let tree = gather_with(&log, &log_options, &["a"], options);
+        assert_eq!(branch_ids(&tree, "a"), [sha(3)]);
+    }
+
+    #[test]
+    fn lists_each_referenced_commit_once() {
+        // b and c are both copies of a, so all three share a's history.
+        let mut log = Log::new();
+        log.commit(4, &[3], &["M", "c"])
+            .commit(3, &[2], &["C100", "a", "b", "C090", "a", "c"])
+            .commit(2, &[1], &["M", "a", "A", "d"])
+            .commit(1, &[], &["A", "a"]);
+        let tree = gather(&log, &["a", "b", "c"]);
+        assert_eq!(branch_ids(&tree, "b"), [sha(3), sha(2), sha(1)]);
+        assert_eq!(branch_ids(&tree, "c"), [sha(4), sha(3), sha(2), sha(1)]);
+
+        let expected: HashSet<SHA1> = (1..5).map(sha).collect();
+        assert_eq!(referenced_commits(&tree), expected);
+        assert!(referenced_commits(&HistoryTree::<SHA1>::new()).is_empty());
+    }
+
+    #[cfg(feature = "bytes-paths")]
+    #[test]
+    fn keeps_non_utf8_paths_through_the_tree() {
+        let old = b"caf\xe9".to_vec();
+        let new = b"caf\xe9\xff".to_vec();
+        let mut log = Log::new();
+        log.commit(3, &[2], &[&b"M"[..], &new])
+            .commit(2, &[1], &[&b"R100"[..], &old, &new])
+            .commit(1, &[], &[&b"A"[..], &old]);
+        let commits = log.parse(&LogOptions::new()).unwrap();
+
+        let paths: PathSet = vec![new.clone()].into_iter().collect();
+        let tree = gather_history_iter(&paths, commit_id, everything, commits);
+        let changes: Vec<_> = BranchIter::new(&tree[&new])
+            .map(|n| n.borrow().path.clone())
+            .collect();
+        assert_eq!(changes, [new.clone(), new, old]);
+    }
+
+    /// Gathers `log` with `max_open_branches(Some(limit))`
+    fn gather_limited(log: &Log, paths: &[&str], limit: usize)
+        -> Result<HistoryTree<SHA1>, GatherError> {
+        let commits = log.parse(&LogOptions::new()).unwrap();
+        let mut options = GatherOptions::new();
+        options.max_open_branches(Some(limit));
+        gather_history_iter_with(&path_set(paths), commit_id, everything, options, commits)
+    }
+
+    #[test]
+    fn doesnt_count_paths_we_havent_reached_as_open() {
+        let mut log = Log::new();
+        log.commit(2, &[1], &["M", "a"])
+            .commit(1, &[], &["A", "a", "A", "b", "A", "c", "A", "d"]);
+        let tree = gather_limited(&log, &["a", "b", "c", "d"], 1).unwrap();
+        assert_eq!(tree.len(), 4);
+    }
+
+    #[test]
+    fn stops_with_too_many_open_branches() {
+        let mut log = Log::new();
+        log.commit(3, &[2], &["R100", "x", "a", "M", "b", "M", "c"])
+            .commit(2, &[1], &["M", "x", "M", "b", "M", "c"])
+            .commit(1, &[], &["A", "x", "A", "b", "A", "c"]);
+        let paths = ["a", "b", "c", "d"];
+        assert_eq!(gather_limited(&log, &paths, 2).err(),
+                   Some(GatherError::TooManyOpenBranches{ limit: 2 }));
+        assert!(gather_limited(&log, &paths, 3).is_ok());
+    }
+
+    #[test]
+    fn diffs_trees() {
+        let tree = gather(&renamed_log(), &["b", "c"]);
+        assert!(diff_trees(&tree, &tree).is_empty());
+        assert!(diff_trees(&tree, &gather(&renamed_log(), &["b", "c"])).is_empty());
+
+        // A new change to b, c is gone, and d shows up
+        let mut log = Log::new();
+        log.commit(4, &[3], &["M", "b", "A", "d", "D", "c"]);
+        let mut newer = log.parse(&LogOptions::new()).unwrap();
+        newer.extend(renamed_log().parse(&LogOptions::new()).unwrap());
+        let new = gather_history_iter(&path_set(&["b", "d"]), commit_id, everything, newer);
+
+        assert_eq!(diff_trees(&tree, &new), TreeDiff{ added: vec![path("d")],
+                                                      removed: vec![path("c")],
+                                                      changed: vec![path("b")] });
+        // Different data at the same commits counts too.
+        let relabeled = gather_history_iter(&path_set(&["b", "c"]),
+                                            |_: &NodeContext| SHA1::NULL, everything,
+                                            renamed_log().parse(&LogOptions::new()).unwrap());
+        assert_eq!(diff_trees(&tree, &relabeled).changed, [path("b"), path("c")]);
+    }
+
+    /// Gathers `log` for `paths`, skipping pure renames if `skip` is set
+    fn gather_skipping_renames(log: &Log, paths: &[&str], skip: bool) -> HistoryTree<SHA1> {
+        let mut options = GatherOptions::new();
+        options.skip_pure_renames(skip);
+        gather_with(log, &LogOptions::new(), paths, options)
+    }
+
+    #[test]
+    fn skips_pure_renames() {
+        let mut log = Log::new();
+        log.commit(4, &[3], &["M", "b"])
+            .commit(3, &[2], &["R100", "a", "b"])
+            .commit(2, &[1], &["M", "a"])
+            .commit(1, &[], &["A", "a"]);
+
+        let tree = gather_skipping_renames(&log, &["b"], false);
+        assert_eq!(branch_ids(&tree, "b"), [sha(4), sha(3), sha(2), sha(1)]);
+
+        let tree = gather_skipping_renames(&log, &["b"], true);
+        assert_eq!(branch_ids(&tree, "b"), [sha(4), sha(2), sha(1)]);
+        let paths: Vec<_> = BranchIter::new(&tree[&path("b")])
+            .map(|n| n.borrow().path.clone())
+            .collect();
+        assert_eq!(paths, [path("b"), path("a"), path("a")]);
+    }
+
+    #[test]
+    fn skips_pure_renames_at_the_head() {
+        let mut log = Log::new();
+        log.commit(3, &[2], &["R100", "a", "b"])
+            .commit(2, &[1], &["M", "a"])
+            .commit(1, &[], &["A", "a"]);
+
+        let tree = gather_skipping_renames(&log, &["b"], true);
+        assert_eq!(tree.len(), 1);
+        // Still keyed by the newest name
+        assert_eq!(branch_ids(&tree, "b"), [sha(2), sha(1)]);
+        assert_eq!(tree[&path("b")].borrow().path, path("a"));
+    }
+
+    #[test]
+    fn keeps_renames_that_changed_the_contents() {
+        let mut log = Log::new();
+        log.commit(2, &[1], &["R099", "a", "b"])
+            .commit(1, &[], &["A", "a"]);
+
+        let tree = gather_skipping_renames(&log, &["b"], true);
+        assert_eq!(branch_ids(&tree, "b"), [sha(2), sha(1)]);
+    }
+
+    #[test]
+    fn only_follows_copies_and_renames_over_the_threshold() {
+        let mut log = Log::new();
+        log.commit(3, &[2], &["C095", "a", "close", "C030", "a", "far",
+                              "R050", "b", "moved", "R049", "c", "gone"])
+            .commit(2, &[1], &["M", "a", "M", "b", "M", "c"])
+            .commit(1, &[], &["A", "a", "A", "b", "A", "c"]);
+        let paths = ["close", "far", "moved", "gone"];
+
+        let tree = gather(&log, &paths);
+        for p in &paths {
+            assert_eq!(branch_ids(&tree, p), [sha(3), sha(2), sha(1)]);
+        }
+
+        let mut options = GatherOptions::new();
+        options.copy_threshold(50).rename_threshold(50);
+        let tree = gather_with(&log, &LogOptions::new(), &paths, options);
+        assert_eq!(branch_ids(&tree, "close"), [sha(3), sha(2), sha(1)]);
+        assert_eq!(branch_ids(&tree, "moved"), [sha(3), sha(2), sha(1)]);
+        // The rest start at the copy or rename, as if they were added there.
+        assert_eq!(branch_ids(&tree, "far"), [sha(3)]);
+        assert_eq!(branch_ids(&tree, "gone"), [sha(3)]);
+        assert_eq!(tree[&path("far")].borrow().change, Change::Copied{ similarity: 30 });
+    }
+
+    #[test]
+    fn survives_self_renames() {
+        let mut log = Log::new();
+        log.commit(3, &[2], &["R100", "a", "a"])
+            .commit(2, &[1], &["C090", "a", "a", "M", "b"])
+            .commit(1, &[], &["A", "a", "A", "b"]);
+
+        let tree = gather(&log, &["a", "b"]);
+        assert_eq!(branch_ids(&tree, "a"), [sha(3), sha(2), sha(1)]);
+        assert!(validate(&tree, &path_set(&["a", "b"])).is_ok());
+        // Each node only leads to the next one, not back to itself.
+        let head = tree[&path("a")].clone();
+        assert!(head.borrow().next_changes().is_empty());
+        assert_eq!(head.borrow().previous.as_ref().unwrap().borrow().next_changes().len(), 1);
+    }
+
+    #[test]
+    fn prunes_nodes_the_filter_rejected() {
+        let mut log = Log::new();
+        log.commit(4, &[3], &["M", "b", "M", "c"])
+            .commit(3, &[2], &["R090", "a", "b"])
+            .commit(2, &[1], &["M", "a", "A", "c"])
+            .commit(1, &[], &["A", "a"]);
+        let commits = log.parse(&LogOptions::new()).unwrap();
+        // Only keep odd commits, so both heads and all of c get filtered out.
+        let odd = |ctx: &NodeContext| ctx.commit.id != sha(4) && ctx.commit.id != sha(2);
+        let mut tree = gather_history_iter(&path_set(&["b", "c"]), commit_id, odd, commits);
+
+        prune_empty(&mut tree);
+        assert_eq!(tree.len(), 1);
+        assert_eq!(branch_ids(&tree, "b"), [sha(3), sha(1)]);
+        assert!(BranchIter::new(&tree[&path("b")]).all(|n| n.borrow().data.is_some()));
+
+        // The oldest node only leads to the one we linked it to.
+        let head = tree[&path("b")].clone();
+        let oldest = head.borrow().previous.clone().unwrap();
+        let next = oldest.borrow().next_changes();
+        assert_eq!(next.len(), 1);
+        assert!(Rc::ptr_eq(&next[0], &head));
+        assert!(validate(&tree, &path_set(&["b"])).is_ok());
+    }
+
+    #[test]
+    fn tracks_whats_still_in_directories() {
+        let mut log = Log::new();
+        log.commit(4, &[3], &["R100", "src/b.rs", "lib/b.rs", "M", "src/a.rs"])
+            .commit(3, &[2], &["R090", "top.rs", "src/c.rs", "D", "src/gone.rs"])
+            .commit(2, &[1], &["A", "src/b.rs", "A", "src/gone.rs", "M", "top.rs"])
+            .commit(1, &[], &["A", "src/a.rs", "A", "top.rs"]);
+
+        for dir in &["src/", "src/**"] {
+            let tree = gather(&log, &[dir]);
+            let mut keys: Vec<_> = tree.keys().cloned().collect();
+            keys.sort();
+            assert_eq!(keys, [path("src/a.rs"), path("src/c.rs")], "tracking {}", dir);
+            assert_eq!(branch_ids(&tree, "src/a.rs"), [sha(4), sha(1)]);
+            // Renamed in, so it keeps its history from outside.
+            assert_eq!(branch_ids(&tree, "src/c.rs"), [sha(3), sha(2), sha(1)]);
+        }
+    }
+
+    #[test]
+    fn makes_one_node_per_path_per_commit() {
+        let mut log = Log::new();
+        log.commit(3, &[2], &["M", "a", "D", "a"])
+            .commit(2, &[1], &["C100", "b", "a", "M", "a"])
+            .commit(1, &[], &["A", "b"]);
+
+        let tree = gather(&log, &["a"]);
+        // The first delta for each path wins.
+        let changes: Vec<_> = BranchIter::new(&tree[&path("a")])
+            .map(|n| { let n = n.borrow(); (n.id, n.change, n.path.clone()) })
+            .collect();
+        assert_eq!(changes, [(sha(3), Change::Modified, path("a")),
+                             (sha(2), Change::Copied{ similarity: 100 }, path("a")),
+                             (sha(1), Change::Added, path("b"))]);
+        assert!(validate(&tree, &path_set(&["a"])).is_ok());
+    }
+
+    #[test]
+    fn gives_octopus_merges_one_node_per_path() {
+        let mut log_options = LogOptions::new();
+        log_options.merges(MergeDiffs::EachParent);
+        let paths = ["a", "b", "c", "d"];
+        let tree = gather_with(&octopus_log(), &log_options, &paths, GatherOptions::new());
+
+        // Each file's history carries on down the side that changed it.
+        assert_eq!(branch_ids(&tree, "a"), [sha(6), sha(5), sha(1)]);
+        assert_eq!(branch_ids(&tree, "b"), [sha(6), sha(4), sha(1)]);
+        assert_eq!(branch_ids(&tree, "d"), [sha(6), sha(2), sha(1)]);
+        assert!(validate(&tree, &path_set(&paths)).is_ok());
+    }
+
+    #[test]
+    fn walks_branches_oldest_first() {
+        let mut log = Log::new();
+        log.commit(4, &[3], &["C100", "b", "d"])
+            .commit(3, &[2], &["R090", "a", "b"])
+            .commit(2, &[1], &["M", "a", "A", "c"])
+            .commit(1, &[], &["A", "a"]);
+        let tree = gather(&log, &["b", "c", "d"]);
+        let forward = oldest_first(&tree);
+
+        for (p, changes) in &forward {
+            let mut newest_first = flatten_branch(&tree[p]);
+            newest_first.reverse();
+            assert!(changes.iter().zip(&newest_first).all(|(a, b)| Rc::ptr_eq(a, b)));
+            assert_eq!(changes.len(), newest_first.len());
+            assert!(Rc::ptr_eq(changes.last().unwrap(), &tree[p]));
+            // Each change is one of the next changes of the one before it.
+            for pair in changes.windows(2) {
+                assert!(pair[0].borrow().next_changes().iter().any(|n| Rc::ptr_eq(n, &pair[1])));
+            }
+        }
+        let ids = |p: &str| -> Vec<_> { forward[&path(p)].iter().map(|n| n.borrow().id).collect() };
+        assert_eq!(ids("d"), [sha(1), sha(2), sha(3), sha(4)]);
+        assert_eq!(ids("c"), [sha(2)]);
+        // d was copied from b, so they share history, and b's head leads on to d.
+        let b_next = tree[&path("b")].borrow().next_changes();
+        assert_eq!(b_next.len(), 1);
+        assert!(Rc::ptr_eq(&b_next[0], &tree[&path("d")]));
+    }
+
+    #[test]
+    fn visits_once_per_commit() {
+        use std::cell::Cell;
+
+        let mut log = Log::new();
+        log.commit(2, &[1], &["M", "a", "M", "b", "M", "c"])
+            .commit(1, &[], &["A", "a", "A", "b", "A", "c"]);
+        let commits = log.parse(&LogOptions::new()).unwrap();
+
+        let calls = Cell::new(0);
+        let visitor = per_commit(|c: &ParsedCommit| { calls.set(calls.get() + 1); c.id });
+        let tree = gather_history_iter(&path_set(&["a", "b", "c"]), visitor, everything,
+                                       commits);
+
+        assert_eq!(calls.get(), 2);
+        for p in &["a", "b", "c"] {
+            let data: Vec<_> = BranchIter::new(&tree[&path(p)])
+                .map(|n| n.borrow().data.as_deref().cloned())
+                .collect();
+            assert_eq!(data, [Some(sha(2)), Some(sha(1))]);
+        }
+    }
+
+    #[test]
+    fn follows_case_only_renames_case_insensitively() {
+        let mut log = Log::new();
+        log.commit(3, &[2], &["M", "foo.rs"])
+            .commit(2, &[1], &["R100", "Foo.rs", "foo.rs"])
+            .commit(1, &[], &["A", "Foo.rs"]);
+        let paths = ["Foo.rs", "foo.rs"];
+
+        let tree = gather(&log, &paths);
+        assert_eq!(tree.len(), 2);
+        assert_eq!(branch_ids(&tree, "Foo.rs"), [sha(1)]);
+
+        let mut options = GatherOptions::new();
+        options.case_insensitive(true);
+        let tree = gather_with(&log, &LogOptions::new(), &paths, options);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [&path("foo.rs")]);
+        assert_eq!(branch_ids(&tree, "foo.rs"), [sha(3), sha(2), sha(1)]);
+    }
+
+    #[test]
+    fn counts_changes_per_author() {
+        let record = |log: &mut Log, n: u32, parent: Option<u32>, author: &str,
+                      deltas: &[&str]| {
+            let email = format!("{}@example.com", author.to_lowercase());
+            log.record(&[&sha(n).to_string(), &format!("{} +0000", when(n)),
+                         &parent.map(|p| sha(p).to_string()).unwrap_or_default(),
+                         author, &email, "Committer", "committer@example.com"],
+                       deltas);
+        };
+        let mut log = Log::new();
+        record(&mut log, 4, Some(3), "Carol", &["M", "b"]);
+        record(&mut log, 3, Some(2), "Alice", &["R090", "a", "b"]);
+        record(&mut log, 2, Some(1), "Bob", &["M", "a"]);
+        record(&mut log, 1, None, "Alice", &["A", "a"]);
+
+        let mut log_options = LogOptions::new();
+        log_options.identities(true);
+        let tree = gather_with(&log, &log_options, &["b"], GatherOptions::new());
+        let head = &tree[&path("b")];
+        assert_eq!(head.borrow().author.as_ref().unwrap().email, "carol@example.com");
+
+        let counts = author_diversity(head);
+        let expected: HashMap<_, _> =
+            vec![("Alice".to_string(), 2), ("Bob".to_string(), 1), ("Carol".to_string(), 1)]
+            .into_iter().collect();
+        assert_eq!(counts, expected);
+
+        // Authors make it into the arena too.
+        let arena = to_arena(&tree);
+        assert_eq!(arena.nodes[arena.heads[&path("b")].0].node.author, head.borrow().author);
+
+        // Without identities, nobody knows who did what.
+        assert!(author_diversity(&gather(&renamed_log(), &["b"])[&path("b")]).is_empty());
+    }
+
+    #[test]
+    fn stops_at_the_subtree_root() {
+        // Walking back in time, root/a leaves the root at commit 3,
+        // where it came from elsewhere/a.
+        let mut log = Log::new();
+        log.commit(4, &[3], &["M", "root/a"])
+            .commit(3, &[2], &["R090", "elsewhere/a", "root/a"])
+            .commit(2, &[1], &["M", "elsewhere/a"])
+            .commit(1, &[], &["A", "elsewhere/a"]);
+
+        let tree = gather(&log, &["root/a"]);
+        assert_eq!(branch_ids(&tree, "root/a"), [sha(4), sha(3), sha(2), sha(1)]);
+
+        let mut options = GatherOptions::new();
+        options.subtree_root(Some(path("root")));
+        let tree = gather_with(&log, &LogOptions::new(), &["root/a"], options);
+        // The branch ends with the rename, and nothing outside the root is in it.
+        assert_eq!(branch_ids(&tree, "root/a"), [sha(4), sha(3)]);
+        assert!(BranchIter::new(&tree[&path("root/a")])
+            .all(|n| path_bytes(&n.borrow().path).starts_with(b"root/")));
+        assert!(validate(&tree, &path_set(&["root/a"])).is_ok());
+    }
+
+    #[test]
+    fn puts_uncommitted_changes_at_the_head() {
+        let repo = Repo::new("dirty-worktree");
+        repo.write("a", "one");
+        repo.git(&["add", "a"]);
+        repo.git(&["commit", "-qm", "Add a"]);
+        repo.write("a", "two");
+
+        let mut options = LogOptions::new();
+        options.repo(Some(repo.path.clone())).working_tree(true);
+        let commits: Vec<_> = CommitIter::new(&options).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        let tree = gather_history_iter(&path_set(&["a"]), commit_id, everything, commits);
+
+        let changes: Vec<_> = BranchIter::new(&tree[&path("a")])
+            .map(|n| { let n = n.borrow(); (n.id, n.change) })
+            .collect();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0], (SHA1::NULL, Change::Modified));
+        assert_eq!(changes[1].1, Change::Added);
+
+        // Without working_tree(), the head is the commit.
+        let commits: Vec<_> = CommitIter::new(options.working_tree(false)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        let tree = gather_history_iter(&path_set(&["a"]), commit_id, everything, commits);
+        assert_eq!(tree[&path("a")].borrow().id, changes[1].0);
+    }
+
+    #[test]
+    fn prunes_short_branches() {
+        // b has three changes (through its rename), and c has one.
+        let mut tree = gather(&renamed_log(), &["b", "c"]);
+        prune_short(&mut tree, 3);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), [&path("b")]);
+        assert_eq!(branch_ids(&tree, "b"), [sha(3), sha(2), sha(1)]);
+
+        prune_short(&mut tree, 4);
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn shares_data_when_visiting_once_per_commit() {
+        use std::cell::Cell;
+
+        let mut log = Log::new();
+        log.commit(2, &[1], &["M", "a", "M", "b"])
+            .commit(1, &[], &["A", "a"])
+            .commit(0, &[], &["A", "b"]);
+        let commits = log.parse(&LogOptions::new()).unwrap();
+
+        let calls = Cell::new(0);
+        let visitor = |ctx: &NodeContext| { calls.set(calls.get() + 1); ctx.commit.id };
+        let mut options = GatherOptions::new();
+        options.visit_once_per_commit(true);
+        let tree = gather_history_iter_with(&path_set(&["a", "b"]), visitor, everything,
+                                            options, commits).unwrap();
+
+        // Once for commit 2, then once each for 1 and 0
+        assert_eq!(calls.get(), 3);
+        let a = tree[&path("a")].borrow().data.clone().unwrap();
+        let b = tree[&path("b")].borrow().data.clone().unwrap();
+        assert!(Rc::ptr_eq(&a, &b));
+        assert_eq!(*a, sha(2));
+    }
+
+    #[test]
+    fn reads_contents_at_commits_and_the_working_tree() {
+        let repo = Repo::new("content");
+        repo.write("a", "one");
+        repo.write("b", "b");
+        repo.git(&["add", "a", "b"]);
+        repo.git(&["commit", "-qm", "Add a and b"]);
+        let first = repo.rev_parse("HEAD");
+        repo.write("a", "two");
+        repo.git(&["commit", "-qam", "Change a"]);
+        repo.git(&["rm", "-q", "b"]);
+        repo.git(&["commit", "-qm", "Remove b"]);
+        repo.write("a", "three");
+
+        assert_eq!(content_at(&repo.path, first, &path("a")).unwrap(), b"one");
+        assert_eq!(content_at(&repo.path, SHA1::NULL, &path("a")).unwrap(), b"three");
+        assert!(content_at(&repo.path, first, &path("missing")).is_err());
+        assert!(content_at(&repo.path, SHA1::NULL, &path("b")).is_err());
+
+        let mut options = LogOptions::new();
+        options.repo(Some(repo.path.clone())).working_tree(true);
+        let commits: Vec<_> = CommitIter::new(&options).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        let visitor = ContentVisitor::new(repo.path.clone());
+        let tree = gather_history_iter(&path_set(&["a", "b"]), visitor, everything, commits);
+        let contents = |p: &str| -> Vec<Option<Vec<u8>>> {
+            BranchIter::new(&tree[&path(p)])
+                .map(|n| match **n.borrow().data.as_ref().unwrap() {
+                    Ok(ref c) => c.clone(),
+                    Err(ref e) => panic!("Couldn't read {}: {}", p, e)
+                })
+                .collect()
+        };
+        let some = |s: &str| Some(s.as_bytes().to_vec());
+        assert_eq!(contents("a"), [some("three"), some("two"), some("one")]);
+        // Nothing's left of b at its deletion.
+        assert_eq!(contents("b"), [None]);
+    }
+
+    #[test]
+    fn follows_files_through_type_changes() {
+        // A file that became a symlink, then pointed somewhere else
+        let symlink = |old_mode: &str, old: u32, new: u32, code: &str| {
+            format!(":{} 120000 {} {} {}", old_mode, sha(old), sha(new), code)
+        };
+        let mut log = Log::new();
+        log.commit(3, &[2], &[symlink("120000", 3, 4, "M"), "f".to_string()])
+            .commit(2, &[1], &[symlink("100644", 2, 3, "T"), "f".to_string()])
+            .commit(1, &[], &[raw(0, 2, "A"), "f".to_string()]);
+        let mut log_options = LogOptions::new();
+        log_options.blobs(true);
+
+        let commits = log.parse(&log_options).unwrap();
+        let changed = &commits[1].deltas[0];
+        assert_eq!(changed.change, Change::TypeChanged);
+        assert_eq!((changed.old_mode, changed.new_mode), (Some(0o100644), Some(0o120000)));
+        assert!(changed.mode_changed());
+        assert!(!commits[0].deltas[0].mode_changed());
+
+        let tree = gather_with(&log, &log_options, &["f"], GatherOptions::new());
+        let changes: Vec<_> = BranchIter::new(&tree[&path("f")])
+            .map(|n| { let n = n.borrow(); (n.id, n.change, n.blob) })
+            .collect();
+        assert_eq!(changes, [(sha(3), Change::Modified, Some(sha(4))),
+                             (sha(2), Change::TypeChanged, Some(sha(3))),
+                             (sha(1), Change::Added, Some(sha(2)))]);
+    }
+
+    #[test]
+    fn groups_branches_by_directory() {
+        let mut log = Log::new();
+        log.commit(1, &[], &["A", "top.rs", "A", "src/lib.rs", "A", "src/net/tcp.rs",
+                             "A", "src/net/udp.rs"]);
+        let tree = gather(&log, &["top.rs", "src/lib.rs", "src/net/tcp.rs", "src/net/udp.rs"]);
+
+        // Each group's paths, sorted, by group
+        let groups = |depth| -> Vec<(GitPath, Vec<GitPath>)> {
+            let mut groups: Vec<_> = group_by_dir(&tree, depth).into_iter()
+                .map(|(dir, branches)| {
+                    let mut paths: Vec<_> = branches.into_iter().map(|(p, _)| p).collect();
+                    paths.sort();
+                    (dir, paths)
+                })
+                .collect();
+            groups.sort();
+            groups
+        };
+
+        let mut everything: Vec<_> = tree.keys().cloned().collect();
+        everything.sort();
+        assert_eq!(groups(0), [(path(""), everything)]);
+        assert_eq!(groups(1), [(path(""), vec![path("top.rs")]),
+                               (path("src"), vec![path("src/lib.rs"), path("src/net/tcp.rs"),
+                                                  path("src/net/udp.rs")])]);
+        let deep = [(path(""), vec![path("top.rs")]),
+                    (path("src"), vec![path("src/lib.rs")]),
+                    (path("src/net"), vec![path("src/net/tcp.rs"), path("src/net/udp.rs")])];
+        assert_eq!(groups(2), deep);
+        assert_eq!(groups(5), deep);
+    }
+
+    #[test]
+    fn renders_mermaid_flowcharts() {
+        // A file renamed on one side of a merge and copied on the other
+        let quoted = "say \"hi\" [1]";
+        let mut log = Log::new();
+        log.commit(4, &[3, 2], NO_DELTAS)
+            .commit(3, &[1], &["R090", "a", quoted])
+            .commit(2, &[1], &["C090", "a", "c[0]"])
+            .commit(1, &[], &["A", "a"]);
+        let tree = gather(&log, &[quoted, "c[0]"]);
+        let lines: Vec<String> = to_mermaid(&tree).lines().map(String::from).collect();
+
+        // Branches are sorted by path, and the first commit is only drawn once.
+        // Quotes get escaped; brackets are fine inside the quoted labels.
+        let expected = [
+            "graph TD".to_string(),
+            "    b0[[\"c[0]\"]]".to_string(),
+            "    b0 --> n0".to_string(),
+            format!("    n0[\"{}\"]", sha(2)),
+            "    n0 --> n1".to_string(),
+            format!("    n1[\"{}\"]", sha(1)),
+            "    b1[[\"say #quot;hi#quot; [1]\"]]".to_string(),
+            "    b1 --> n2".to_string(),
+            format!("    n2[\"{}\"]", sha(3)),
+            "    n2 --> n1".to_string(),
+        ];
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn splits_copies_into_their_own_branches() {
+        let mut log = Log::new();
+        log.commit(5, &[4], &["M", "x", "M", "y"])
+            .commit(4, &[3], &["M", "x"])
+            .commit(3, &[2], &["C100", "a", "x", "C100", "a", "y"])
+            .commit(2, &[1], &["M", "a"])
+            .commit(1, &[], &["A", "a"]);
+        let tree = gather(&log, &["x", "y"]);
+
+        assert_eq!(branch_ids(&tree, "x"), [sha(5), sha(4), sha(3), sha(2), sha(1)]);
+        assert_eq!(branch_ids(&tree, "y"), [sha(5), sha(3), sha(2), sha(1)]);
+
+        let x = flatten_branch(&tree[&path("x")]);
+        let y = flatten_branch(&tree[&path("y")]);
+        // Each copy gets its own node from the copy on...
+        assert!(!Rc::ptr_eq(&x[2], &y[1]));
+        assert_eq!(x[2].borrow().path, path("x"));
+        assert_eq!(y[1].borrow().path, path("y"));
+        // ...but they share a's history from before it.
+        assert!(x[3..].iter().zip(&y[2..]).all(|(a, b)| Rc::ptr_eq(a, b)));
+        assert_eq!(x[3].borrow().path, path("a"));
+        assert!(validate(&tree, &path_set(&["x", "y"])).is_ok());
+    }
+
+    #[test]
+    fn combines_with_the_lowest_matching_path() {
+        for _ in 0..10 {
+            let mut newer = Log::new();
+            newer.commit(5, &[4], &["M", "moved"])
+                .commit(4, &[], &["A", "moved"]);
+            let mut older = Log::new();
+            older.commit(3, &[2], &["M", "c", "M", "b"])
+                .commit(2, &[1], &["A", "c", "A", "b"])
+                .commit(1, &[], &["A", "a"]);
+
+            let trees = vec![gather(&newer, &["moved"]), gather(&older, &["a", "b", "c"])];
+            // Everything matches, so the tie-break picks.
+            let tree = combine(trees, |_, _| true);
+
+            assert_eq!(branch_ids(&tree, "moved"), [sha(5), sha(4), sha(1)]);
+            assert!(!tree.contains_key(&path("a")));
+            assert_eq!(branch_ids(&tree, "b"), [sha(3), sha(2)]);
+            assert_eq!(branch_ids(&tree, "c"), [sha(3), sha(2)]);
+        }
+    }
+}
@@ -6,11 +6,18 @@
 //!
 //! See `main.rs` for a quick demo.
 
-extern crate time;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
 pub mod parsing;
 pub mod history;
+pub mod blame;
 
 pub use self::types::*;
 
 mod types;
+
+#[cfg(test)]
+mod testing;
@@ -1,79 +1,114 @@
-//! A demo app that gets the --oneline of every commit for a given file.
-//! Since this does so once per diff per commit, it is hilariously inefficient,
-//! but very easy to validate by comparing a given file's history to
+//! A demo app that prints the --oneline of every commit for each file.
+//! It's very easy to validate by comparing a given file's history to
 //! `git log --follow --oneline <file>`.
+//!
+//! Pass `--summary` to instead print a quick overview of each file's activity:
+//! how many times it changed, when it first and last changed,
+//! and how many names it's had.
 
-extern crate time;
+extern crate git_historian;
 
-use std::io::{BufReader, BufRead};
-use std::process::{Command, Stdio};
-use std::str;
+use std::collections::HashSet;
+use std::env;
+use std::process;
 use std::sync::mpsc::sync_channel;
 use std::thread;
+use std::time::SystemTime;
 
-mod parsing;
-mod history;
-mod types;
-
-use history::*;
-use parsing::*;
-use types::*;
+use git_historian::*;
+use git_historian::history::*;
+use git_historian::parsing::{self, LogOptions};
 
 fn main() {
-    // let args: Vec<String> = env::args().collect();
-    let (tx, rx) = sync_channel(0);
+    let args: Vec<String> = env::args().collect();
+    let summary = args.iter().any(|a| a == "--summary");
 
-    thread::spawn(move || parsing::get_history(&tx));
+    // Commits' subjects are all we need past what's on each node.
+    let mut options = LogOptions::new();
+    options.subjects(!summary);
 
-    let paths = get_tracked_files();
+    let paths = parsing::tracked_files(&options).unwrap_or_else(|e| {
+        eprintln!("Couldn't list the tracked files: {}", e);
+        process::exit(1);
+    });
 
-    let history = gather_history(&paths, &get_id, |_| true, &rx);
-
-    for (key, val) in history {
-        println!("{}", key);
-        print_history(&val);
-    }
-}
+    let (tx, rx) = sync_channel(0);
 
-/// *Warning:* This currently assumes the working directory is the top-level Git
-/// directory. This should be fixed at some point...
-fn get_tracked_files() -> PathSet {
-    let mut ret = PathSet::new();
+    let parser = thread::spawn(move || parsing::get_history_with(&options, &tx));
 
-    // TODO: Make sure we're in the top level dir (change to it?)
-    let child = Command::new("git")
-        .arg("ls-files")
-        .stdout(Stdio::piped())
-        .spawn().unwrap();
+    if summary {
+        // Everything we need is already on the nodes.
+        let history = gather_history(&paths, |_: &NodeContext| (), |_| true, &rx);
+        check_parser(parser);
 
-    let br = BufReader::new(child.stdout.unwrap());
+        for (key, val) in history {
+            print_summary(&key, &val);
+        }
+    }
+    else {
+        let history = gather_history(&paths,
+                                     |ctx: &NodeContext| ctx.commit.subject.clone()
+                                                            .unwrap_or_default(),
+                                     |_| true, &rx);
+        check_parser(parser);
+
+        for (key, val) in history {
+            println!("{}", display_path(&key));
+            print_history(&val);
+        }
+    }
+}
 
-    for file in br.lines().map(|l| l.unwrap()) {
-        ret.insert(file);
+/// Bails if the parser thread failed, since we'd only have part of the history.
+fn check_parser(parser: thread::JoinHandle<Result<(), parsing::ParseError>>) {
+    if let Err(e) = parser.join().expect("The parser thread panicked") {
+        eprintln!("Couldn't get the history: {}", e);
+        process::exit(1);
     }
+}
 
-    ret
+/// Prints each change's abbreviated commit hash and subject
+fn print_history(head: &Link<HistoryNode<String>>) {
+    for node in BranchIter::new(head) {
+        let nb = node.borrow();
+        if let Some(ref subject) = nb.data {
+            println!("\t{} {}", &nb.id.to_string()[..7], subject);
+        }
+    }
 }
 
-fn get_id(c: &ParsedCommit) -> String {
+fn print_summary<T>(key: &GitPathRef, head: &Link<HistoryNode<T>>) {
+    let mut changes = 0;
+    let mut names = HashSet::new();
+    // History is newest-first, so the last node we see is the oldest.
+    let last = head.borrow().when;
+    let mut first = last;
+
+    for node in BranchIter::new(head) {
+        let nb = node.borrow();
+        changes += 1;
+        names.insert(nb.path.clone());
+        first = nb.when;
+    }
 
-    str::from_utf8(&Command::new("git")
-        .arg("log")
-        .arg("--oneline")
-        .arg("--no-walk")
-        .arg(c.id.to_string())
-        .stdout(Stdio::piped())
-        .output().unwrap()
-        .stdout).unwrap().trim().to_string()
+    println!("{}: {} changes from {} to {}, {} name(s)",
+             display_path(key), changes, format_date(first), format_date(last),
+             names.len());
 }
 
-fn print_history<T>(node: &Link<HistoryNode<T>>)
-    where T: std::fmt::Display {
-    let nb = node.borrow();
-    if let Some(ref data) = nb.data {
-        println!("\t{}", data);
-    }
-    if let Some(ref prev) = nb.previous {
-        print_history(prev)
-    }
+/// Formats the UTC date of `t` as YYYY-MM-DD
+fn format_date(t: SystemTime) -> String {
+    // Turn days since the epoch into a Gregorian date,
+    // per http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = unix_seconds(t).div_euclid(86_400) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+                       - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
 }
@@ -7,11 +7,22 @@
 //! diffs for merges (i.e. only the changes resulting from conflict resolution)
 //! as Git does.
 
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
 use std::io::{self, BufReader, BufRead};
-use std::process::{Child, Command, Stdio};
-use std::sync::mpsc::SyncSender;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
+use std::mem;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use time::Timespec;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 use types::*;
 
@@ -19,9 +30,35 @@ use types::*;
 #[derive(Debug, Clone)]
 pub struct ParsedCommit {
     pub id: SHA1,
-    /// The Unix timestamp (in seconds) of the commit
-    pub when: Timespec,
+    /// When the commit was written, unless `LogOptions::timestamp()`
+    /// says otherwise. Git only keeps whole seconds.
+    /// (See `unix_seconds()` for the Unix timestamp.)
+    pub when: SystemTime,
+    /// The time zone `when` was in, in minutes east of UTC
+    /// (e.g., -480 for Pacific Standard Time)
+    pub utc_offset: i16,
+    /// The commit's parents, in order.
+    /// Root commits have none, and merges have two or more.
+    pub parents: Vec<SHA1>,
     pub deltas: Vec<FileDelta>,
+    /// If the commit's timestamp couldn't be parsed and `when` was set to the
+    /// epoch instead (see `LogOptions::lenient_timestamps()`),
+    /// the line we got instead
+    pub bad_timestamp: Option<String>,
+    /// Delta lines that couldn't be parsed and were left out of `deltas`
+    /// (see `LogOptions::lenient_deltas()` and `LogOptions::lossy_paths()`)
+    pub skipped_deltas: Vec<String>,
+    /// Whether the commit was signed, and if the signature checked out
+    /// (if `LogOptions::signatures()` was set)
+    pub signature: Option<SignatureStatus>,
+    /// The first line of the commit message (if `LogOptions::subjects()` was set)
+    pub subject: Option<String>,
+    /// The rest of the commit message (if `LogOptions::bodies()` was set)
+    pub body: Option<String>,
+    /// Who wrote the commit (if `LogOptions::identities()` was set)
+    pub author: Option<Identity>,
+    /// Who committed the commit (if `LogOptions::identities()` was set)
+    pub committer: Option<Identity>,
 }
 
 impl Default for ParsedCommit {
@@ -29,138 +66,2475 @@ impl Default for ParsedCommit {
     {
         ParsedCommit {
             id: SHA1::default(),
-            when: Timespec::new(0, 0),
-            deltas: Vec::new()
+            when: UNIX_EPOCH,
+            utc_offset: 0,
+            parents: Vec::new(),
+            deltas: Vec::new(),
+            bad_timestamp: None,
+            skipped_deltas: Vec::new(),
+            signature: None,
+            subject: None,
+            body: None,
+            author: None,
+            committer: None
         }
     }
 }
 
-/// Starts the `git log` process with the desired config
-fn start_history_process() -> Result<Child, io::Error> {
-    Command::new("git")
-        .arg("log")
-        .arg("--name-status")
-        .arg("-M")
-        .arg("-C")
-        .arg("--pretty=format:%H%n%at") // Commit hash, newline, unix time
+/// The state of a commit's GPG signature, as reported by `git log`'s `%G?`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SignatureStatus {
+    /// A good, valid signature (`G`)
+    Good,
+    /// A bad signature (`B`)
+    Bad,
+    /// A good signature with unknown validity (`U`)
+    UnknownValidity,
+    /// A good signature that has expired (`X`)
+    Expired,
+    /// A good signature made by an expired key (`Y`)
+    ExpiredKey,
+    /// A good signature made by a revoked key (`R`)
+    RevokedKey,
+    /// A signature that can't be checked, e.g., because the key is missing (`E`)
+    CannotCheck,
+    /// No signature (`N`)
+    Unsigned,
+}
+
+impl SignatureStatus {
+    /// Parses the code `%G?` gives us.
+    fn parse(code: &str) -> Option<SignatureStatus> {
+        match code {
+            "G" => Some(SignatureStatus::Good),
+            "B" => Some(SignatureStatus::Bad),
+            "U" => Some(SignatureStatus::UnknownValidity),
+            "X" => Some(SignatureStatus::Expired),
+            "Y" => Some(SignatureStatus::ExpiredKey),
+            "R" => Some(SignatureStatus::RevokedKey),
+            "E" => Some(SignatureStatus::CannotCheck),
+            "N" => Some(SignatureStatus::Unsigned),
+            _ => None
+        }
+    }
+}
+
+/// Someone who wrote or committed a commit
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Identity {
+    pub name: String,
+    pub email: String,
+}
+
+/// A commit, as we present it to the rest of the world
+///
+/// `ParsedCommit` is whatever we happened to scrape out of `git log`,
+/// and changes as the parser does. This is the stable version:
+/// everything past the ID, time, and deltas is optional, and is `None`
+/// when the log we parsed didn't include it (not when it was empty).
+/// Parse diagnostics like `ParsedCommit::bad_timestamp` don't make the trip.
+#[derive(Debug, Clone)]
+pub struct Commit {
+    pub id: SHA1,
+    /// When the commit was made (see `ParsedCommit::when`)
+    pub when: SystemTime,
+    /// The commit's changes to files
+    pub deltas: Vec<FileDelta>,
+    pub author: Option<Identity>,
+    pub committer: Option<Identity>,
+    /// The first line of the commit message
+    pub subject: Option<String>,
+    /// The rest of the commit message
+    pub body: Option<String>,
+    /// The commit's parents, in order. Root commits have none.
+    pub parents: Option<Vec<SHA1>>,
+    /// Branches and tags pointing at the commit
+    pub refs: Option<Vec<String>>,
+}
+
+impl From<ParsedCommit> for Commit {
+    fn from(p: ParsedCommit) -> Commit {
+        Commit {
+            id: p.id,
+            when: p.when,
+            deltas: p.deltas,
+            author: p.author,
+            committer: p.committer,
+            subject: p.subject,
+            body: p.body,
+            parents: Some(p.parents),
+            refs: None
+        }
+    }
+}
+
+/// The fields at the top of each commit in the log, before its deltas
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum HeaderFormat {
+    /// Just the commit hash (`%H`), then its parents and anything else
+    /// `LogOptions` asks for.
+    /// `ParsedCommit::when` is left at the epoch.
+    ///
+    /// This is still our own format (see `LogOptions::command_line()`),
+    /// not whatever `git log --format=%H` prints - the parents are always
+    /// there, and it has to be `--pretty=format:`, since `--format=`
+    /// (`tformat:`) ends each header with an extra NUL.
+    Hash,
+    /// The commit hash, then its Unix timestamp and time zone
+    /// (`%ad` with `--date=raw`, e.g. `1500000000 -0800`,
+    /// or `%cd` - see `LogOptions::timestamp()`), then the rest.
+    /// If the time zone is missing, we assume UTC.
+    #[default]
+    HashAndTimestamp,
+}
+
+/// Which of a commit's timestamps goes in `ParsedCommit::when`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum TimestampKind {
+    /// When the commit was written (`%ad`)
+    #[default]
+    Author,
+    /// When the commit was committed (`%cd`), which is later for commits
+    /// that were rebased, cherry-picked, or applied from a patch
+    Committer,
+}
+
+/// How `git log` diffs merge commits (see `LogOptions::merges()`)
+///
+/// Octopus merges (with three or more parents) are handled like any other.
+/// Either way, a merge has at most one delta per path, so it gets at most
+/// one node in a file's history. Nodes only have one `previous`, so that
+/// node's history continues with the next older change to the file in the log,
+/// whichever parent's side it's on - same as for the commits on each side.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum MergeDiffs {
+    /// Git's default: merges have no deltas, so a file's history only
+    /// follows the changes made on each side.
+    #[default]
+    Off,
+    /// `--cc`: just the changes that don't match any parent, i.e.,
+    /// conflict resolutions and other edits made in the merge itself.
+    /// Their line counts (see `LogOptions::numstat()`) are relative to
+    /// the first parent.
+    Combined,
+    /// `-m`: the changes relative to each parent.
+    /// A file changed relative to several parents only gets one delta
+    /// (from the first parent Git diffs it against).
+    ///
+    /// Each delta's `FileDelta::parent` says which parent it's relative to.
+    /// Git leaves out the diffs against parents the merge matches,
+    /// and doesn't say which ones it left out, so if any are missing
+    /// (say, for a `git merge -s ours`), we can't tell, and it's `None`.
+    EachParent,
+}
+
+/// The fields of each commit's header after the hash, in the order we ask
+/// Git for them. Each one ends with a NUL.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum HeaderField {
+    Timestamp(TimestampKind),
+    /// Space-separated hashes, or nothing for a root commit
+    Parents,
+    Signature,
+    Subject,
+    /// Can span any number of lines
+    Body,
+    /// Author name and email, then committer name and email,
+    /// separated by NULs
+    Identities,
+}
+
+impl HeaderField {
+    /// Returns the `--pretty=format:` placeholder for the field
+    fn placeholder(self) -> &'static str {
+        match self {
+            // With --date=raw, e.g., "1500000000 -0800"
+            HeaderField::Timestamp(TimestampKind::Author) => "%ad",
+            HeaderField::Timestamp(TimestampKind::Committer) => "%cd",
+            HeaderField::Parents => "%P",
+            HeaderField::Signature => "%G?",
+            HeaderField::Subject => "%s",
+            HeaderField::Body => "%b",
+            HeaderField::Identities => "%an%x00%ae%x00%cn%x00%ce",
+        }
+    }
+}
+
+/// Configures the `git log` invocation used by `get_history_with()`
+/// (or run it yourself with `spawn()`)
+///
+/// The defaults reproduce the behavior of plain `get_history()`.
+#[derive(Debug, Clone, Default)]
+pub struct LogOptions {
+    header: HeaderFormat,
+    numstat: bool,
+    blobs: bool,
+    raw_lines: bool,
+    lenient_timestamps: bool,
+    lenient_deltas: bool,
+    working_tree: bool,
+    max_count: Option<usize>,
+    sandbox_config: bool,
+    timeout: Option<Duration>,
+    delta_pool: Option<DeltaPool>,
+    signatures: bool,
+    repo: Option<PathBuf>,
+    subjects: bool,
+    bodies: bool,
+    identities: bool,
+    timestamp: TimestampKind,
+    lossy_paths: bool,
+    merges: MergeDiffs,
+    revspec: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    rename_threshold: Option<u8>,
+    copy_threshold: Option<u8>,
+    find_copies_harder: bool,
+    git_program: Option<String>,
+    git_dir: Option<PathBuf>,
+    work_tree: Option<PathBuf>,
+}
+
+impl LogOptions {
+    pub fn new() -> LogOptions { LogOptions::default() }
+
+    /// Sets which fields each commit's header has.
+    ///
+    /// The first commit is checked against this,
+    /// and we complain loudly if it doesn't look right.
+    pub fn header(&mut self, format: HeaderFormat) -> &mut LogOptions {
+        self.header = format;
+        self
+    }
+
+    /// Also gathers line counts for each delta (see `FileDelta::numstat`).
+    ///
+    /// Git won't print `--numstat` alongside `--name-status`,
+    /// so this switches the log to `--raw --numstat` and matches up
+    /// the two lists, which come out in the same order.
+    /// Binary detection (`FileDelta::is_binary()`) depends on this.
+    pub fn numstat(&mut self, enable: bool) -> &mut LogOptions {
+        self.numstat = enable;
+        self
+    }
+
+    /// Records the blob IDs and file modes on either side of each delta
+    /// (see `FileDelta::old_blob`, `FileDelta::new_blob`,
+    /// and `FileDelta::mode_changed()`).
+    ///
+    /// Like `numstat()`, this switches the log to `--raw`.
+    pub fn blobs(&mut self, enable: bool) -> &mut LogOptions {
+        self.blobs = enable;
+        self
+    }
+
+    /// Keeps each delta's source line in `FileDelta::raw`,
+    /// which is handy when tracking down parser bugs.
+    ///
+    /// This costs a copy of every delta line in the history,
+    /// so it's off by default.
+    pub fn keep_raw_lines(&mut self, enable: bool) -> &mut LogOptions {
+        self.raw_lines = enable;
+        self
+    }
+
+    /// Sets commits' timestamps to the epoch if they can't be parsed,
+    /// instead of giving up on the whole history.
+    /// The offending line is kept in `ParsedCommit::bad_timestamp`.
+    ///
+    /// Some old, imported repos genuinely have zero or garbage dates.
+    pub fn lenient_timestamps(&mut self, enable: bool) -> &mut LogOptions {
+        self.lenient_timestamps = enable;
+        self
+    }
+
+    /// Skips delta lines that can't be parsed instead of giving up,
+    /// so that odd output from some exotic Git version doesn't cost us
+    /// the whole history. The rest of the commit's deltas are kept,
+    /// and the offending lines go in `ParsedCommit::skipped_deltas`.
+    pub fn lenient_deltas(&mut self, enable: bool) -> &mut LogOptions {
+        self.lenient_deltas = enable;
+        self
+    }
+
+    /// Replaces anything that isn't valid UTF-8 in delta paths with U+FFFD
+    /// instead of skipping the delta.
+    ///
+    /// This only matters without the `bytes-paths` feature,
+    /// which keeps such paths as they are.
+    /// By default, deltas with such paths go in `ParsedCommit::skipped_deltas`
+    /// (and `tracked_files()` leaves the files out), so one oddly-named file
+    /// doesn't cost us the rest of the history. With this set, they're kept,
+    /// but mangled paths won't match anything you ask for by name.
+    pub fn lossy_paths(&mut self, enable: bool) -> &mut LogOptions {
+        self.lossy_paths = enable;
+        self
+    }
+
+    /// Emits a pseudo-commit for uncommitted changes (`git diff HEAD`)
+    /// before the rest of the history, so they show up at the top of each
+    /// branch.
+    ///
+    /// The pseudo-commit's ID is `SHA1::NULL` and its timestamp is the current
+    /// time. Untracked files aren't included, and nothing is emitted if the
+    /// working tree is clean.
+    /// Git doesn't hash working tree files for `git diff`,
+    /// so their `FileDelta::new_blob` is always `None`.
+    pub fn working_tree(&mut self, enable: bool) -> &mut LogOptions {
+        self.working_tree = enable;
+        self
+    }
+
+    /// Only walks the `count` most recent commits (`git log --max-count`).
+    ///
+    /// This is the quickest way to bound the work for "recent activity",
+    /// but since the history is cut off at an arbitrary point, branches will
+    /// often start abruptly, with no `Added` node at the end.
+    pub fn max_count(&mut self, count: Option<usize>) -> &mut LogOptions {
+        self.max_count = count;
+        self
+    }
+
+    /// Sets how similar (in percent) Git needs a file to be to its old path
+    /// to call the change a rename (`-M<n>%`), instead of Git's default (50%).
+    ///
+    /// This decides what Git reports in the first place;
+    /// `history::GatherOptions::rename_threshold()` decides which of those
+    /// renames we follow.
+    pub fn rename_threshold(&mut self, percent: Option<u8>) -> &mut LogOptions {
+        self.rename_threshold = percent;
+        self
+    }
+
+    /// Like `rename_threshold()`, but for copies (`-C<n>%`)
+    pub fn copy_threshold(&mut self, percent: Option<u8>) -> &mut LogOptions {
+        self.copy_threshold = percent;
+        self
+    }
+
+    /// Looks for the sources of copies among every file in the commit's
+    /// parent (`--find-copies-harder`), not just the files it changed.
+    ///
+    /// This catches copies of files that were left alone,
+    /// but it's very expensive on big repos.
+    pub fn find_copies_harder(&mut self, enable: bool) -> &mut LogOptions {
+        self.find_copies_harder = enable;
+        self
+    }
+
+    /// Only walks commits made after `date` (`git log --since`),
+    /// which can be anything Git understands, e.g., `2 weeks ago` or
+    /// `2017-06-01`.
+    ///
+    /// Like `max_count()`, this cuts the history off at an arbitrary point,
+    /// so branches often start abruptly - even partway through a rename,
+    /// with the old name still waiting on older history that never comes.
+    /// That's expected, not an error.
+    pub fn since(&mut self, date: Option<String>) -> &mut LogOptions {
+        self.since = date;
+        self
+    }
+
+    /// Only walks commits made before `date` (`git log --until`)
+    /// (see `since()`)
+    pub fn until(&mut self, date: Option<String>) -> &mut LogOptions {
+        self.until = date;
+        self
+    }
+
+    /// Walks the given revisions instead of everything leading up to `HEAD`,
+    /// e.g., `HEAD~50`, `v1.0..v2.0`, or `--all`.
+    ///
+    /// Anything `git log` takes works, since it's passed along as-is.
+    /// Keep in mind that history is only gathered for paths that the range
+    /// touches - a file that didn't change in `v1.0..v2.0` gets no branch.
+    pub fn revspec(&mut self, revs: Option<String>) -> &mut LogOptions {
+        self.revspec = revs;
+        self
+    }
+
+    /// Runs Git with a fixed configuration, so that your `~/.gitconfig`
+    /// (or your coworker's) can't change the results.
+    ///
+    /// The user and system config files are ignored (by pointing
+    /// `GIT_CONFIG_GLOBAL` and `GIT_CONFIG_SYSTEM` at `/dev/null`,
+    /// or `NUL` on Windows), and since the repo's own config still applies,
+    /// we override the settings that affect what we parse:
+    ///
+    /// - `diff.renames=copies`
+    /// - `color.ui=never`
+    /// - `log.showSignature=false`
+    /// - `core.quotePath=false`
+    pub fn sandbox_config(&mut self, enable: bool) -> &mut LogOptions {
+        self.sandbox_config = enable;
+        self
+    }
+
+    /// Kills `git log` and gives up with `ParseError::Timeout` if it runs
+    /// longer than `limit`, so a corrupt or enormous repo can't hang us.
+    ///
+    /// By then, some commits have probably been sent already. They're fine,
+    /// but the history they make up is incomplete - the `Err` is the only
+    /// thing telling you so. There's no limit by default.
+    pub fn timeout(&mut self, limit: Option<Duration>) -> &mut LogOptions {
+        self.timeout = limit;
+        self
+    }
+
+    /// Runs Git in `repo` (with `git -C`) instead of the current directory.
+    ///
+    /// This can be the top of a worktree or any directory inside one -
+    /// either way, we get the whole repo's history.
+    pub fn repo(&mut self, repo: Option<PathBuf>) -> &mut LogOptions {
+        self.repo = repo;
+        self
+    }
+
+    /// Points Git at the repository `dir` (with `--git-dir`),
+    /// e.g., a bare repository on a server.
+    ///
+    /// Unlike `repo()`, this is the `.git` directory itself (or the bare repo),
+    /// not a worktree. Git treats a relative path as relative to `repo()`,
+    /// if that's set.
+    pub fn git_dir(&mut self, dir: Option<PathBuf>) -> &mut LogOptions {
+        self.git_dir = dir;
+        self
+    }
+
+    /// Uses `dir` as the worktree (with `--work-tree`),
+    /// which only matters for `working_tree()` and `tracked_files()`.
+    pub fn work_tree(&mut self, dir: Option<PathBuf>) -> &mut LogOptions {
+        self.work_tree = dir;
+        self
+    }
+
+    /// Runs `program` instead of the usual Git (see `git_program()`),
+    /// e.g., `/opt/git-2.40/bin/git`.
+    pub fn git_program(&mut self, program: Option<String>) -> &mut LogOptions {
+        self.git_program = program;
+        self
+    }
+
+    /// Checks each commit's GPG signature (with `%G?`) and records the result
+    /// in `ParsedCommit::signature`.
+    ///
+    /// The status is its own header field, after the parents.
+    /// Git runs GPG for every signed commit to do this,
+    /// so expect it to be much slower.
+    pub fn signatures(&mut self, enable: bool) -> &mut LogOptions {
+        self.signatures = enable;
+        self
+    }
+
+    /// Picks which timestamp goes in `ParsedCommit::when`:
+    /// when each commit was written (the default) or when it was committed.
+    pub fn timestamp(&mut self, kind: TimestampKind) -> &mut LogOptions {
+        self.timestamp = kind;
+        self
+    }
+
+    /// Picks whether (and how) merges get deltas.
+    ///
+    /// By default they don't, so a change made while resolving a conflict
+    /// is missing from the history, and so is the merge.
+    /// With `MergeDiffs::EachParent`, all of a merge's diffs end up in the one
+    /// `ParsedCommit`, each delta tagged with its parent (if we can tell).
+    pub fn merges(&mut self, mode: MergeDiffs) -> &mut LogOptions {
+        self.merges = mode;
+        self
+    }
+
+    /// Records the subject (the first line) of each commit's message
+    /// in `ParsedCommit::subject`.
+    pub fn subjects(&mut self, enable: bool) -> &mut LogOptions {
+        self.subjects = enable;
+        self
+    }
+
+    /// Records the rest of each commit's message in `ParsedCommit::body`.
+    ///
+    /// Since the body can be any number of lines (blank ones included),
+    /// we ask Git to end it with a NUL.
+    pub fn bodies(&mut self, enable: bool) -> &mut LogOptions {
+        self.bodies = enable;
+        self
+    }
+
+    /// Records the name and email of each commit's author and committer
+    /// in `ParsedCommit::author` and `ParsedCommit::committer`.
+    pub fn identities(&mut self, enable: bool) -> &mut LogOptions {
+        self.identities = enable;
+        self
+    }
+
+    /// Builds each commit's list of deltas in a spare one from `pool`
+    /// (if there are any), instead of allocating a new one every commit.
+    ///
+    /// The consumer hands lists back with `DeltaPool::recycle()`
+    /// once it's done with each commit - see
+    /// `history::GatherOptions::recycle_deltas()`.
+    pub fn delta_pool(&mut self, pool: Option<DeltaPool>) -> &mut LogOptions {
+        self.delta_pool = pool;
+        self
+    }
+
+    /// Returns the command (program name first) that `get_history_with()`
+    /// will run with these options, without running it
+    ///
+    /// Environment variables set for the command are given by `environment()`.
+    pub fn command_line(&self) -> Vec<OsString> {
+        let mut args = vec!["log".to_string()];
+        args.extend(self.diff_args());
+        args.push(format!("--pretty=format:{}", self.pretty_format()));
+        args.push("--date=raw".to_string());
+        match self.merges {
+            MergeDiffs::Off => { }
+            MergeDiffs::Combined => { args.push("--cc".to_string()); }
+            MergeDiffs::EachParent => { args.push("-m".to_string()); }
+        }
+        if let Some(n) = self.max_count {
+            args.push(format!("--max-count={}", n));
+        }
+        if let Some(ref date) = self.since {
+            args.push(format!("--since={}", date));
+        }
+        if let Some(ref date) = self.until {
+            args.push(format!("--until={}", date));
+        }
+        if let Some(ref revs) = self.revspec {
+            args.push(revs.clone());
+            // In case a revision has the same name as a file
+            args.push("--".to_string());
+        }
+
+        let mut argv = self.git();
+        argv.extend(args.into_iter().map(OsString::from));
+        argv
+    }
+
+    /// Returns the command that gathers uncommitted changes
+    /// if `working_tree()` is set
+    pub fn working_tree_command_line(&self) -> Vec<OsString> {
+        let mut argv = self.git();
+        argv.push("diff".into());
+        argv.extend(self.diff_args().into_iter().map(OsString::from));
+        argv.push("HEAD".into());
+        argv
+    }
+
+    /// Checks for settings that can't work, so we can complain before
+    /// running Git instead of partway through (or with a panic)
+    ///
+    /// `spawn()` and `CommitIter::new()` check this themselves.
+    /// None of these would make us panic, just make Git fail,
+    /// so the other functions that run Git (like `tracked_files()`) don't bother.
+    pub fn validate(&self) -> Result<(), ParseError> {
+        let bad = |why: String| Err(ParseError::BadOptions(why));
+
+        for &(name, threshold) in &[("Rename", self.rename_threshold),
+                                    ("Copy", self.copy_threshold)] {
+            if let Some(n) = threshold {
+                if n > 100 {
+                    return bad(format!("{} threshold {}% is over 100%", name, n));
+                }
+            }
+        }
+
+        if self.git_program.as_ref().is_some_and(|p| p.is_empty()) {
+            return bad("The Git program is empty".to_string());
+        }
+        if self.revspec.as_ref().is_some_and(|r| r.is_empty()) {
+            return bad("The revspec is empty (use None for HEAD)".to_string());
+        }
+        Ok(())
+    }
+
+    /// Starts `git log` with these options (see `command_line()`),
+    /// with its output piped back to us
+    ///
+    /// This is for parsing the output yourself (say, with `parse_log()`).
+    /// Wait on the child when you're done with it.
+    pub fn spawn(&self) -> Result<Child, ParseError> {
+        self.validate()?;
+        start_process(&self.command_line(), self).map_err(ParseError::Spawn)
+    }
+
+    /// Returns the environment variables we set when running Git
+    pub fn environment(&self) -> Vec<(String, String)> {
+        if self.sandbox_config {
+            vec![("GIT_CONFIG_GLOBAL".to_string(), NULL_DEVICE.to_string()),
+                 ("GIT_CONFIG_SYSTEM".to_string(), NULL_DEVICE.to_string())]
+        }
+        else {
+            Vec::new()
+        }
+    }
+
+    /// Returns the fields of each commit's header after its hash
+    fn header_fields(&self) -> Vec<HeaderField> {
+        let mut fields = Vec::new();
+        if self.header == HeaderFormat::HashAndTimestamp {
+            fields.push(HeaderField::Timestamp(self.timestamp));
+        }
+        fields.push(HeaderField::Parents);
+        if self.signatures {
+            fields.push(HeaderField::Signature);
+        }
+        if self.identities {
+            fields.push(HeaderField::Identities);
+        }
+        if self.subjects {
+            fields.push(HeaderField::Subject);
+        }
+        if self.bodies {
+            fields.push(HeaderField::Body);
+        }
+        fields
+    }
+
+    /// Returns the `--pretty=format:` placeholders for the header,
+    /// each ending in a NUL (see `LogParser`)
+    fn pretty_format(&self) -> String {
+        let mut format = "%H%x00".to_string();
+        for field in self.header_fields() {
+            format.push_str(field.placeholder());
+            format.push_str("%x00");
+        }
+        format
+    }
+
+    /// Returns the program and any arguments that precede the Git command
+    ///
+    /// Paths are passed along as-is, so they can be anything the OS allows.
+    fn git(&self) -> Vec<OsString> {
+        let mut argv = vec![OsString::from(self.git_program.clone().unwrap_or_else(git_program))];
+
+        if let Some(ref repo) = self.repo {
+            argv.push("-C".into());
+            argv.push(repo.into());
+        }
+
+        // --git-dir=<path>, without going through a String
+        let flag = |name: &str, path: &Path| {
+            let mut arg = OsString::from(name);
+            arg.push(path);
+            arg
+        };
+        if let Some(ref dir) = self.git_dir {
+            argv.push(flag("--git-dir=", dir));
+        }
+        if let Some(ref dir) = self.work_tree {
+            argv.push(flag("--work-tree=", dir));
+        }
+
+        if self.sandbox_config {
+            for setting in &["diff.renames=copies",
+                             "color.ui=never",
+                             "log.showSignature=false",
+                             "core.quotePath=false"] {
+                argv.push("-c".into());
+                argv.push(setting.into());
+            }
+        }
+
+        argv
+    }
+
+    /// Returns the arguments controlling how each diff is printed,
+    /// which `git log` and `git diff` share
+    fn diff_args(&self) -> Vec<String> {
+        // NUL-terminate everything, so paths come out unquoted
+        // and can't be confused with anything else (see `LogParser`).
+        let mut args = vec!["-z".to_string()];
+
+        if self.numstat || self.blobs {
+            args.push("--raw".to_string());
+            // Full blob IDs, please.
+            args.push("--no-abbrev".to_string());
+            if self.numstat {
+                args.push("--numstat".to_string());
+            }
+        }
+        else {
+            args.push("--name-status".to_string());
+        }
+
+        let detect = |flag: &str, threshold: Option<u8>| match threshold {
+            Some(n) => format!("{}{}%", flag, n),
+            None => flag.to_string()
+        };
+        args.push(detect("-M", self.rename_threshold));
+        args.push(detect("-C", self.copy_threshold));
+        if self.find_copies_harder {
+            args.push("--find-copies-harder".to_string());
+        }
+        args
+    }
+}
+
+/// Spare `ParsedCommit::deltas` lists, passed from the consumer of the commits
+/// back to the parser (see `LogOptions::delta_pool()`)
+///
+/// Clones share the same pool.
+#[derive(Debug, Clone, Default)]
+pub struct DeltaPool {
+    spares: Arc<Mutex<Vec<Vec<FileDelta>>>>,
+}
+
+impl DeltaPool {
+    /// Holding more spares than this is just hoarding memory -
+    /// the parser only needs one at a time.
+    const MAX_SPARES: usize = 16;
+
+    pub fn new() -> DeltaPool { DeltaPool::default() }
+
+    /// Gives a commit's deltas back to the parser to reuse.
+    pub fn recycle(&self, mut deltas: Vec<FileDelta>) {
+        deltas.clear();
+        let mut spares = self.spares.lock().unwrap();
+        if spares.len() < DeltaPool::MAX_SPARES {
+            spares.push(deltas);
+        }
+    }
+
+    /// Returns a spare (empty) list of deltas, or a new one if there are none.
+    fn take(&self) -> Vec<FileDelta> {
+        self.spares.lock().unwrap().pop().unwrap_or_default()
+    }
+}
+
+/// Ways `get_history()` and friends can fail
+///
+/// Any commits sent before the error are only part of the history.
+#[derive(Debug)]
+pub enum ParseError {
+    /// We couldn't start Git. Is it installed?
+    Spawn(io::Error),
+    /// We couldn't read Git's output or wait for it to finish.
+    Read(io::Error),
+    /// Git gave up. It probably said why on stderr.
+    Failed(ExitStatus),
+    /// `git log` ran past `LogOptions::timeout()`.
+    Timeout,
+    /// The path given to `LogOptions::repo()` isn't in a Git repository.
+    /// Git's complaint is included.
+    NotARepository{ path: PathBuf, message: String },
+    /// A commit didn't start with a hash.
+    BadSha(SHA1ParseError),
+    /// The first commit didn't match `LogOptions::header()` -
+    /// the log probably wasn't printed with `-z` and `format`,
+    /// the `--pretty=format:` we ask for (see `LogOptions::command_line()`).
+    BadHeader{ line: String, expected: &'static str, format: String },
+    /// A commit's timestamp wasn't a number
+    /// (see `LogOptions::lenient_timestamps()`).
+    BadTimestamp{ commit: SHA1, line: String },
+    /// A commit's parents weren't a list of hashes.
+    BadParents{ commit: SHA1, line: String },
+    /// A commit's signature status wasn't one we know (see `SignatureStatus`).
+    BadSignature{ commit: SHA1, line: String },
+    /// A commit's author and committer line didn't have all four fields.
+    BadIdentities{ commit: SHA1, line: String },
+    /// A delta line couldn't be parsed (see `LogOptions::lenient_deltas()`).
+    BadDelta{ commit: SHA1, message: String },
+    /// The `LogOptions` don't make sense (see `LogOptions::validate()`),
+    /// so we didn't run Git.
+    BadOptions(String),
+}
+
+impl Error for ParseError {}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Spawn(ref e) => write!(f, "Couldn't run Git: {}", e),
+            ParseError::Read(ref e) => write!(f, "Couldn't read Git's output: {}", e),
+            ParseError::Failed(status) => write!(f, "Git failed ({})", status),
+            ParseError::Timeout => write!(f, "git log ran past its timeout"),
+            ParseError::NotARepository{ ref path, ref message } =>
+                write!(f, "{} isn't a Git repository: {}", path.display(), message),
+            ParseError::BadSha(ref e) => write!(f, "Expected a commit hash: {}", e),
+            ParseError::BadHeader{ ref line, expected, ref format } =>
+                write!(f, "Expected {}, got {:?}. \
+                           Was the log printed with -z and --pretty=format:{}?",
+                       expected, line, format),
+            ParseError::BadTimestamp{ commit, ref line } =>
+                write!(f, "Couldn't parse timestamp {:?} of commit {}", line, commit),
+            ParseError::BadParents{ commit, ref line } =>
+                write!(f, "Couldn't parse parents {:?} of commit {}", line, commit),
+            ParseError::BadSignature{ commit, ref line } =>
+                write!(f, "Expected a signature status for commit {}, got {:?}",
+                       commit, line),
+            ParseError::BadIdentities{ commit, ref line } =>
+                write!(f, "Expected author and committer names and emails \
+                           for commit {}, got {:?}", commit, line),
+            ParseError::BadDelta{ commit, ref message } =>
+                write!(f, "{} in commit {}", message, commit),
+            ParseError::BadOptions(ref why) => write!(f, "Bad log options: {}", why),
+        }
+    }
+}
+
+/// Kills a child process if it runs past a deadline
+/// (see `LogOptions::timeout()`)
+struct Watchdog {
+    done: mpsc::Sender<()>,
+    thread: thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    fn start(child: Arc<Mutex<Child>>, limit: Duration) -> Watchdog {
+        let (done, finished) = mpsc::channel();
+        let thread = thread::spawn(move || match finished.recv_timeout(limit) {
+            Err(RecvTimeoutError::Timeout) => {
+                // It might have just exited, which is fine.
+                let _ = child.lock().unwrap().kill();
+                true
+            }
+            _ => false
+        });
+        Watchdog{ done, thread }
+    }
+
+    /// Stops the watchdog, returning true if it killed the child.
+    fn finish(self) -> bool {
+        let _ = self.done.send(());
+        self.thread.join().expect("The watchdog thread panicked")
+    }
+}
+
+/// An empty file to point Git's config at (see `LogOptions::sandbox_config()`)
+#[cfg(not(windows))]
+pub(crate) const NULL_DEVICE: &str = "/dev/null";
+#[cfg(windows)]
+pub(crate) const NULL_DEVICE: &str = "NUL";
+
+/// Returns the Git we run: the `GIT` environment variable if it's set,
+/// or `git` from the `PATH` otherwise
+///
+/// `LogOptions::git_program()` overrides this for the log.
+pub fn git_program() -> String {
+    match env::var("GIT") {
+        Ok(ref g) if !g.is_empty() => g.clone(),
+        _ => "git".to_string()
+    }
+}
+
+/// Lists the files Git tracks under the directory `root`
+/// (or everything, if `root` is empty), relative to the top of the repo
+///
+/// This is a handy way to build the `PathSet` for following a subtree
+/// (see `history::GatherOptions::subtree_root()`).
+pub fn tracked_files_under(root: &GitPathRef) -> io::Result<PathSet> {
+    // :(top) matches from the top of the repo, wherever we're run from.
+    let mut spec = OsString::from(":(top)");
+    spec.push(path_to_os(root)?);
+
+    ls_files(Command::new(git_program()).arg("ls-files"), &[spec], false)
+}
+
+/// Lists the files Git tracks in `repo` that match any of the shell-style
+/// glob `patterns`, e.g. `*.rs` or `src/**/*.c`, relative to the top of the repo
+///
+/// Like in a shell, `*` doesn't match across slashes, so `*.rs` only matches
+/// files at the top. Use `**/*.rs` for all of them. Patterns go to
+/// `git ls-files` as pathspecs, so ignored and untracked files never match.
+/// An empty list of patterns matches nothing.
+pub fn tracked_files_matching(patterns: &[&str], repo: &Path) -> io::Result<PathSet> {
+    if patterns.is_empty() {
+        return Ok(PathSet::new());
+    }
+
+    let specs: Vec<_> = patterns.iter()
+        .map(|p| OsString::from(format!(":(top,glob){}", p)))
+        .collect();
+
+    ls_files(Command::new(git_program()).arg("-C").arg(repo).arg("ls-files"), &specs, false)
+}
+
+/// Lists every file Git tracks in the repo `options` point at,
+/// relative to the top of the repo
+///
+/// This is the usual `PathSet` for following a whole repo,
+/// and unlike `tracked_files_under()`, it honors `LogOptions::repo()`,
+/// `git_dir()`, and so on.
+/// A bare repo has no index to ask, so we list the files in `HEAD` instead.
+/// Paths that aren't valid UTF-8 are handled like `LogOptions::lossy_paths()`
+/// says (without the `bytes-paths` feature).
+pub fn tracked_files(options: &LogOptions) -> io::Result<PathSet> {
+    let argv = options.git();
+    let git = || {
+        let mut c = Command::new(&argv[0]);
+        c.args(&argv[1..]).envs(options.environment());
+        c
+    };
+
+    let bare = git().arg("rev-parse").arg("--is-bare-repository").output()?;
+    if !bare.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&bare.stderr).trim().to_string()));
+    }
+
+    if bare.stdout.trim_ascii() == b"true" {
+        read_paths(git().arg("ls-tree").arg("-r").arg("-z")
+                        .arg("--name-only").arg("--full-tree")
+                        .arg("HEAD"),
+                   options.lossy_paths)
+    }
+    else {
+        ls_files(git().arg("ls-files"), &[OsString::from(":(top)")], options.lossy_paths)
+    }
+}
+
+/// Runs the given `git ls-files` command for `specs`, collecting its output
+fn ls_files(command: &mut Command, specs: &[OsString], lossy: bool) -> io::Result<PathSet> {
+    // --full-name prints paths the same way git log does.
+    read_paths(command.arg("-z").arg("--full-name").arg("--").args(specs), lossy)
+}
+
+/// Runs the given command, collecting the NUL-separated paths it prints
+///
+/// Paths that aren't valid UTF-8 are mangled if `lossy` is set, and left out
+/// otherwise, since the log skips their deltas (see `LogOptions::lossy_paths()`).
+fn read_paths(command: &mut Command, lossy: bool) -> io::Result<PathSet> {
+    let output = command.output()?;
+
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+
+    Ok(output.stdout.split(|&b| b == 0)
+        .filter(|l| !l.is_empty())
+        .filter_map(|l| convert_path(l.to_vec(), lossy))
+        .collect())
+}
+
+/// Makes sure `repo` is in a Git repository, so that we can give a clear error
+/// instead of choking on `git log`'s (lack of) output
+fn check_repo(repo: &Path, options: &LogOptions) -> Result<(), ParseError> {
+    let mut argv = options.git();
+    argv.push("rev-parse".into());
+    argv.push("--git-dir".into());
+
+    let output = Command::new(&argv[0])
+        .args(&argv[1..])
+        .envs(options.environment())
+        .output();
+
+    let message = match output {
+        Ok(ref o) if o.status.success() => return Ok(()),
+        Ok(o) => String::from_utf8_lossy(&o.stderr).trim().to_string(),
+        Err(e) => e.to_string()
+    };
+    Err(ParseError::NotARepository{ path: repo.to_path_buf(), message })
+}
+
+/// Returns true if the repo has a `HEAD` commit, i.e., it isn't brand new
+/// (or on a branch with no commits yet)
+fn has_head(options: &LogOptions) -> bool {
+    let argv = options.git();
+    Command::new(&argv[0])
+        .args(&argv[1..])
+        .arg("rev-parse").arg("--verify").arg("--quiet").arg("HEAD")
+        .envs(options.environment())
+        .stdout(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+/// Starts the given command with its output piped back to us
+fn start_process(argv: &[OsString], options: &LogOptions) -> Result<Child, io::Error> {
+    Command::new(&argv[0])
+        .args(&argv[1..])
+        .envs(options.environment())
         .stdout(Stdio::piped())
         .spawn()
 }
 
-/// Parses the Git history and emits a series of `ParsedCommits`
-///
-/// The parsed commits are pushed to a `SyncSender`,
-/// and are assumed to be consumed by another thread.
-pub fn get_history(sink: &SyncSender<ParsedCommit>) {
+/// Gathers uncommitted changes into a pseudo-commit
+/// (see `LogOptions::working_tree()`)
+fn parse_working_tree(options: &LogOptions) -> Result<ParsedCommit, ParseError> {
+    let mut child = start_process(&options.working_tree_command_line(), options)
+        .map_err(ParseError::Spawn)?;
+    let mut br = BufReader::new(child.stdout.take().unwrap());
+
+    let mut commit = ParsedCommit{ id: SHA1::NULL,
+                                   when: SystemTime::now(),
+                                   ..ParsedCommit::default() };
+    let mut lines = DeltaLines::default();
+
+    let mut parse = || -> Result<(), ParseError> {
+        let mut token = Vec::new();
+        let mut record = Vec::new();
+        while next_token(&mut br, &mut token)? {
+            if token.is_empty() { continue; }
+
+            copy_token(&mut record, 0, &token);
+            let len = 1 + paths_after(&record[0]);
+            for i in 1..len {
+                if !next_token(&mut br, &mut token)? {
+                    return Err(ParseError::BadDelta {
+                        commit: SHA1::NULL,
+                        message: "git diff ended partway through a delta".to_string()
+                    });
+                }
+                copy_token(&mut record, i, &token);
+            }
+            add_delta(options, &mut commit, &mut lines, &record[..len])?;
+        }
+        Ok(())
+    };
+    let result = parse();
+
+    if result.is_err() {
+        let _ = child.kill();
+    }
+    let status = child.wait().map_err(ParseError::Read)?;
+    result?;
+    if !status.success() {
+        return Err(ParseError::Failed(status));
+    }
+    Ok(commit)
+}
+
+/// Parses the Git history and emits a series of `ParsedCommits`
+///
+/// The parsed commits are pushed to a `SyncSender`,
+/// and are assumed to be consumed by another thread.
+/// If something goes wrong, the commits sent so far are only part of
+/// the history, and the error says why.
+/// If the receiving end hangs up, we stop Git and return `Ok` -
+/// the consumer has everything it wanted.
+pub fn get_history(sink: &SyncSender<ParsedCommit>) -> Result<(), ParseError> {
+    get_history_with(&LogOptions::default(), sink)
+}
+
+/// Like `get_history()`, but for the repository at (or containing) `repo`
+/// instead of the current directory (see `LogOptions::repo()`)
+pub fn get_history_in(repo: &Path, sink: &SyncSender<ParsedCommit>) -> Result<(), ParseError> {
+    get_history_with(LogOptions::new().repo(Some(repo.to_path_buf())), sink)
+}
+
+/// Like `get_history()`, but runs `git log` with the given options
+pub fn get_history_with(options: &LogOptions, sink: &SyncSender<ParsedCommit>)
+    -> Result<(), ParseError> {
+    for commit in CommitIter::new(options)? {
+        if !commit_sink(commit?, sink) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Parses `git log` output from `reader` instead of running Git,
+/// sending each commit to `sink`
+///
+/// `reader` has to hold exactly what Git prints for
+/// `options.command_line()`, which is `git log -z` with our own
+/// `--pretty=format:`. For the default options, that's
+///
+/// ```text
+/// <hash>\0<timestamp> <zone>\0<parents>\0\n<status>\0<path>\0...\0\0<next hash>\0...
+/// ```
+///
+/// Each header field (see `LogOptions::header()`) ends with a NUL,
+/// the first delta starts with a newline, and each status and path of the
+/// deltas gets its own token (renames and copies have the old path, then the
+/// new one). Commits are separated by an extra NUL,
+/// and a commit with no deltas goes right from its header to that NUL.
+/// `-z` is a must: logs printed without it, or with some other format
+/// (including `--format=`, which isn't `--pretty=format:`), won't parse,
+/// and we say so with a `ParseError::BadHeader` on the first commit.
+///
+/// Options about running Git, like `repo()` and `timeout()`, are ignored.
+/// This is mostly for feeding the parser canned output.
+pub fn parse_log<R: BufRead>(reader: R, options: &LogOptions,
+                             sink: &SyncSender<ParsedCommit>) -> Result<(), ParseError> {
+    let mut parser = LogParser::new(reader, options);
+    while let Some(commit) = parser.next_commit(options)? {
+        if !commit_sink(commit, sink) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Parses the Git history one commit at a time, for when spinning up
+/// a thread to run `get_history()` is more trouble than it's worth
+///
+/// After an error, the iterator ends. Either way, Git is waited on
+/// (or killed, if we gave up) once we're done.
+pub struct CommitIter {
+    options: LogOptions,
+    /// The working tree's pseudo-commit (see `LogOptions::working_tree()`),
+    /// if we haven't given it out yet
+    working_tree: Option<ParsedCommit>,
+    parser: LogParser<BufReader<ChildStdout>>,
+    child: Arc<Mutex<Child>>,
+    watchdog: Option<Watchdog>,
+    done: bool,
+}
+
+impl CommitIter {
+    /// Starts `git log` with the given options
+    pub fn new(options: &LogOptions) -> Result<CommitIter, ParseError> {
+        options.validate()?;
+        if let Some(ref repo) = options.repo {
+            check_repo(repo, options)?;
+        }
+
+        // A new repo with no commits has no HEAD for git log to start from,
+        // so it would fail. There's no history to walk, so ask for none.
+        // (git diff HEAD would fail too, so there's no working tree either.)
+        let mut options = options.clone();
+        if options.revspec.is_none() && !has_head(&options) {
+            options.revspec(Some("--all".to_string()))
+                .max_count(Some(0))
+                .working_tree(false);
+        }
+        let options = &options;
+
+        let working_tree = if options.working_tree {
+                Some(parse_working_tree(options)?).filter(|c| !c.deltas.is_empty())
+            }
+            else {
+                None
+            };
+
+        let mut child = options.spawn()?;
+        let reader = BufReader::new(child.stdout.take().unwrap());
+        let child = Arc::new(Mutex::new(child));
+        let watchdog = options.timeout.map(|limit| Watchdog::start(child.clone(), limit));
+
+        Ok(CommitIter{ options: options.clone(),
+                       working_tree,
+                       parser: LogParser::new(reader, options),
+                       child,
+                       watchdog,
+                       done: false })
+    }
+
+    /// Waits for Git once we're out of commits (or gave up),
+    /// passing along `last` unless something went wrong.
+    fn finish(&mut self, last: Result<Option<ParsedCommit>, ParseError>)
+        -> Option<Result<ParsedCommit, ParseError>> {
+        self.done = true;
+
+        // Don't leave Git hanging if we gave up partway through.
+        if last.is_err() {
+            let _ = self.child.lock().unwrap().kill();
+        }
+        let timed_out = self.watchdog.take().is_some_and(Watchdog::finish);
+        let status = self.child.lock().unwrap().wait();
+
+        // If git log was killed, the last commit is probably missing some deltas
+        // (and any other error is a symptom of that).
+        if timed_out {
+            return Some(Err(ParseError::Timeout));
+        }
+        let last = match last {
+            Ok(l) => l,
+            Err(e) => return Some(Err(e))
+        };
+        match status {
+            Ok(s) if s.success() => last.map(Ok),
+            Ok(s) => Some(Err(ParseError::Failed(s))),
+            Err(e) => Some(Err(ParseError::Read(e)))
+        }
+    }
+}
+
+impl Drop for CommitIter {
+    fn drop(&mut self) {
+        // If we were dropped partway through, stop Git instead of leaving it
+        // blocked on a full pipe.
+        if !self.done {
+            let _ = self.child.lock().unwrap().kill();
+            if let Some(w) = self.watchdog.take() {
+                w.finish();
+            }
+            let _ = self.child.lock().unwrap().wait();
+        }
+    }
+}
+
+impl Iterator for CommitIter {
+    type Item = Result<ParsedCommit, ParseError>;
+
+    fn next(&mut self) -> Option<Result<ParsedCommit, ParseError>> {
+        if self.done {
+            return None;
+        }
+        if let Some(wt) = self.working_tree.take() {
+            return Some(Ok(wt));
+        }
+
+        match self.parser.next_commit(&self.options) {
+            // We only know the last commit is complete once Git has exited.
+            Ok(Some(c)) if !self.parser.is_done() => Some(Ok(c)),
+            other => self.finish(other)
+        }
+    }
+}
+
+/// The parser that reads `git log -z` output, a commit at a time
+///
+/// With `-z`, everything we care about is terminated by a NUL:
+/// the hash and each header field (we end each one with `%x00`),
+/// then each status, numstat, and path of the deltas.
+/// Git puts a newline between the header and the deltas,
+/// and an extra NUL after each commit, which shows up as an empty token.
+/// Since paths get their own tokens, they can hold anything but a NUL,
+/// and can never be mistaken for anything else.
+struct LogParser<R: BufRead> {
+    reader: R,
+    /// What comes after each commit's hash (see `LogOptions::header_fields()`)
+    fields: Vec<HeaderField>,
+    /// The `--pretty=format:` for `fields`, to complain about if the log
+    /// doesn't match it
+    format: String,
+    /// Until we've finished the first commit, check that the header format
+    /// is what we were told it is.
+    first_record: bool,
+    /// How the current commit's --numstat records match up with its deltas
+    lines: DeltaLines,
+    /// A wide commit can have tens of thousands of tokens,
+    /// so reuse one buffer for all of them.
+    token: Vec<u8>,
+    /// Likewise, each delta's tokens are copied into these buffers
+    /// (see `copy_token()`) instead of taking `token`'s.
+    record: Vec<Vec<u8>>,
+    /// A token we read too far (the next commit's hash),
+    /// to hand back before reading any more
+    pending: Option<Vec<u8>>,
+    /// Set once we've hit the end of the output
+    done: bool,
+}
+
+impl<R: BufRead> LogParser<R> {
+    fn new(reader: R, options: &LogOptions) -> LogParser<R> {
+        LogParser{ reader,
+                   fields: options.header_fields(),
+                   format: options.pretty_format(),
+                   first_record: true,
+                   lines: DeltaLines::default(),
+                   token: Vec::new(),
+                   record: Vec::new(),
+                   pending: None,
+                   done: false }
+    }
+
+    /// Returns true once the last commit has been returned
+    fn is_done(&self) -> bool { self.done }
+
+    /// Reads the next token into `self.token`, returning false at the end
+    /// of the output.
+    fn next_token(&mut self) -> Result<bool, ParseError> {
+        if let Some(t) = self.pending.take() {
+            self.token = t;
+            return Ok(true);
+        }
+        next_token(&mut self.reader, &mut self.token)
+    }
+
+    /// Reads the next token of the header (as a string, since none of it is
+    /// a path), complaining if the log ends first.
+    fn header_token(&mut self) -> Result<String, ParseError> {
+        if !self.next_token()? {
+            return Err(self.bad_header(String::new(),
+                "the rest of the commit's header before the end of the log"));
+        }
+        Ok(String::from_utf8_lossy(&self.token).into_owned())
+    }
+
+    /// Complains that the log doesn't look like what we asked Git for.
+    fn bad_header(&self, line: String, expected: &'static str) -> ParseError {
+        ParseError::BadHeader{ line, expected, format: self.format.clone() }
+    }
+
+    /// Parses the next commit, returning `None` once there are no more.
+    fn next_commit(&mut self, options: &LogOptions)
+        -> Result<Option<ParsedCommit>, ParseError> {
+        if self.done || !self.next_token()? {
+            self.done = true;
+            return Ok(None);
+        }
+
+        let mut commit = new_commit(options);
+        self.parse_header(options, &mut commit)?;
+        self.parse_deltas(options, &mut commit)?;
+
+        // With -m, Git prints the merge again for each parent it differs from.
+        // Fold those diffs into the first.
+        if options.merges == MergeDiffs::EachParent && commit.parents.len() > 1 {
+            // Where each diff starts in commit.deltas
+            let mut starts = vec![0];
+            while !self.done {
+                if !self.next_token()? {
+                    self.done = true;
+                    break;
+                }
+                if SHA1::parse(&String::from_utf8_lossy(&self.token)).ok() != Some(commit.id) {
+                    self.pending = Some(mem::take(&mut self.token));
+                    break;
+                }
+
+                let mut again = ParsedCommit::default();
+                self.parse_header(options, &mut again)?;
+                self.parse_deltas(options, &mut again)?;
+                commit.skipped_deltas.append(&mut again.skipped_deltas);
+                starts.push(commit.deltas.len());
+                commit.deltas.append(&mut again.deltas);
+            }
+
+            // The diffs come in the same order as the parents,
+            // but we don't know which parents were skipped for being the same.
+            if starts.len() == commit.parents.len() {
+                starts.push(commit.deltas.len());
+                for (parent, diff) in commit.parents.iter().zip(starts.windows(2)) {
+                    for delta in &mut commit.deltas[diff[0]..diff[1]] {
+                        delta.parent = Some(*parent);
+                    }
+                }
+            }
+
+            let mut seen = PathSet::new();
+            commit.deltas.retain(|d| seen.insert(d.path.clone()));
+        }
+
+        self.first_record = false;
+        Ok(Some(commit))
+    }
+
+    /// Parses a commit's header into `commit`, starting from the hash
+    /// in `self.token`.
+    fn parse_header(&mut self, options: &LogOptions, commit: &mut ParsedCommit)
+        -> Result<(), ParseError> {
+        let hash = String::from_utf8_lossy(&self.token);
+        commit.id = match SHA1::parse(&hash) {
+            Ok(id) => id,
+            // Without -z, the whole log ends up in one token.
+            Err(_) if self.first_record => {
+                let line = hash.lines().next().unwrap_or_default().to_string();
+                return Err(self.bad_header(line, "a commit hash followed by a NUL"));
+            }
+            Err(e) => return Err(ParseError::BadSha(e))
+        };
 
-    enum ParseState { // Used for the state machine below
-        Hash,
-        Timestamp,
-        Changes
+        for i in 0..self.fields.len() {
+            self.parse_field(self.fields[i], options, commit)?;
+        }
+        Ok(())
     }
 
-    let child = start_history_process().expect("Couldn't open repo history");
-    let br = BufReader::new(child.stdout.unwrap());
+    /// Parses a commit's deltas into `commit`, up to the NUL after them.
+    fn parse_deltas(&mut self, options: &LogOptions, commit: &mut ParsedCommit)
+        -> Result<(), ParseError> {
+        self.lines.clear();
+        let mut first = true;
+        loop {
+            if !self.next_token()? {
+                self.done = true;
+                break;
+            }
+
+            // With --cc, Git separates a merge's header from its deltas
+            // with a NUL instead of a newline.
+            if first && self.token.is_empty() &&
+               options.merges == MergeDiffs::Combined && commit.parents.len() > 1 {
+                first = false;
+                continue;
+            }
 
-    let mut state = ParseState::Hash;
-    let mut current_commit = ParsedCommit::default();
+            // The NUL after the commit
+            if self.token.is_empty() {
+                break;
+            }
 
-    for line in br.lines().map(|l| l.unwrap()) {
+            // The first delta comes after a newline.
+            let mut start = 0;
+            if first {
+                if self.token[0] == b'\n' {
+                    start = 1;
+                }
+                else if self.first_record {
+                    return Err(self.bad_header(String::from_utf8_lossy(&self.token).into_owned(),
+                                               "a delta or the end of the first commit"));
+                }
+                first = false;
+            }
+            // With --format= (tformat:), Git doesn't separate commits with
+            // a NUL, so the next commit runs right into this one's deltas.
+            // No change code looks like a hash.
+            else if self.first_record && SHA1::parse(&String::from_utf8_lossy(&self.token)).is_ok() {
+                return Err(self.bad_header(String::from_utf8_lossy(&self.token).into_owned(),
+                                           "a NUL between the first two commits"));
+            }
 
-        if line.is_empty() { continue; } // Blow through empty lines
+            copy_token(&mut self.record, 0, &self.token[start..]);
+            let len = 1 + paths_after(&self.record[0]);
+            for i in 1..len {
+                if !self.next_token()? {
+                    return Err(ParseError::BadDelta {
+                        commit: commit.id,
+                        message: "The log ended partway through a delta".to_string()
+                    });
+                }
+                copy_token(&mut self.record, i, &self.token);
+            }
+            add_delta(options, commit, &mut self.lines, &self.record[..len])?;
+        }
+        Ok(())
+    }
 
-        let next_state;
-        match state {
-            ParseState::Hash => {
-                current_commit.id = SHA1::parse(&line).unwrap();
-                next_state = ParseState::Timestamp;
+    /// Parses a field of the commit's header into `commit`.
+    fn parse_field(&mut self, field: HeaderField, options: &LogOptions,
+                   commit: &mut ParsedCommit) -> Result<(), ParseError> {
+        match field {
+            HeaderField::Timestamp(_) => {
+                let line = self.header_token()?;
+                match parse_timestamp(&line) {
+                    Some((sec, offset)) => {
+                        commit.when = from_unix_seconds(sec);
+                        commit.utc_offset = offset;
+                    }
+                    None if options.lenient_timestamps => {
+                        commit.bad_timestamp = Some(line);
+                    }
+                    None if self.first_record => {
+                        return Err(self.bad_header(line,
+                                                   "a timestamp after the first commit hash"));
+                    }
+                    None => {
+                        return Err(ParseError::BadTimestamp{ commit: commit.id, line });
+                    }
+                };
             }
 
-            ParseState::Timestamp => {
-                current_commit.when = Timespec{ sec: line.parse().unwrap(),
-                                                nsec: 0 };
-                next_state = ParseState::Changes;
+            HeaderField::Parents => {
+                let line = self.header_token()?;
+                let parents: Result<Vec<SHA1>, _> = line.split(' ')
+                    .filter(|p| !p.is_empty())
+                    .map(SHA1::parse)
+                    .collect();
+                match parents {
+                    Ok(p) => { commit.parents = p; }
+                    Err(_) if self.first_record => {
+                        return Err(self.bad_header(line, "the first commit's parents"));
+                    }
+                    Err(_) => {
+                        return Err(ParseError::BadParents{ commit: commit.id, line });
+                    }
+                }
             }
 
-            ParseState::Changes => {
-                // If we get the next hash, we're done with the previous commit.
-                if let Ok(id) = SHA1::parse(&line) {
-                    commit_sink(current_commit, sink);
-                    current_commit = ParsedCommit::default();
+            HeaderField::Signature => {
+                let line = self.header_token()?;
+                match SignatureStatus::parse(&line) {
+                    Some(sig) => { commit.signature = Some(sig); }
+                    None => {
+                        return Err(ParseError::BadSignature{ commit: commit.id, line });
+                    }
+                };
+            }
 
-                    // We just got the OID of the next commit,
-                    // so proceed to reading the timestamp
-                    current_commit.id = id;
-                    next_state = ParseState::Timestamp;
+            HeaderField::Identities => {
+                let mut parts = Vec::with_capacity(4);
+                for _ in 0..4 {
+                    let part = self.header_token()?;
+                    // A name or email can't have a newline, so this must be
+                    // the deltas.
+                    if part.starts_with('\n') {
+                        return Err(ParseError::BadIdentities {
+                            commit: commit.id,
+                            line: parts.join("\0")
+                        });
+                    }
+                    parts.push(part);
                 }
-                else {
-                    // Keep chomping deltas
-                    next_state = state;
+                let mut parts = parts.into_iter();
+                let mut next = || parts.next().unwrap();
+                commit.author = Some(Identity{ name: next(), email: next() });
+                commit.committer = Some(Identity{ name: next(), email: next() });
+            }
+
+            HeaderField::Subject => {
+                commit.subject = Some(self.header_token()?);
+            }
+
+            HeaderField::Body => {
+                let mut body = self.header_token()?;
+                // Git ends the body with a newline (or several).
+                let len = body.trim_end_matches('\n').len();
+                body.truncate(len);
+                commit.body = Some(body);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a `--date=raw` timestamp, e.g. `1500000000 +0530`,
+/// into seconds since the epoch and minutes east of UTC
+fn parse_timestamp(s: &str) -> Option<(i64, i16)> {
+    let mut tokens = s.split(' ');
+    let sec = tokens.next()?.parse().ok()?;
+
+    let offset = match tokens.next() {
+        None => 0,
+        Some(tz) => {
+            let (sign, digits) = match tz.as_bytes().first() {
+                Some(b'+') => (1, &tz[1..]),
+                Some(b'-') => (-1, &tz[1..]),
+                _ => return None
+            };
+            if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let hours: i16 = digits[..2].parse().ok()?;
+            let minutes: i16 = digits[2..].parse().ok()?;
+            sign * (hours * 60 + minutes)
+        }
+    };
+
+    if tokens.next().is_some() {
+        return None;
+    }
+    Some((sec, offset))
+}
+
+/// Returns an empty commit to parse the next one into,
+/// borrowing its deltas from `LogOptions::delta_pool()` if we can
+fn new_commit(options: &LogOptions) -> ParsedCommit {
+    match options.delta_pool {
+        Some(ref pool) => ParsedCommit{ deltas: pool.take(), ..ParsedCommit::default() },
+        None => ParsedCommit::default()
+    }
+}
+
+/// Tracks how a commit's `--numstat` records line up with its deltas
+///
+/// Git prints them in the same order, but if we skipped a bad delta
+/// (see `LogOptions::lenient_deltas()`), its stats have nowhere to go.
+#[derive(Default)]
+struct DeltaLines {
+    /// The index in `ParsedCommit::deltas` of each delta line,
+    /// or `None` if we skipped it
+    deltas: Vec<Option<usize>>,
+    /// How many `--numstat` lines we've seen so far
+    stats_seen: usize,
+    /// For `--cc` merges, Git prints stats relative to the first parent
+    /// before the deltas, so we match them up by path instead.
+    /// The stat is `None` if we skipped it.
+    merge_stats: Vec<(Vec<u8>, Option<NumStat>)>,
+}
+
+impl DeltaLines {
+    /// Resets for the next commit, keeping our allocation
+    fn clear(&mut self) {
+        self.deltas.clear();
+        self.stats_seen = 0;
+        self.merge_stats.clear();
+    }
+}
+
+/// Copies `token` into `record[i]`, reusing its allocation if it has one
+fn copy_token(record: &mut Vec<Vec<u8>>, i: usize, token: &[u8]) {
+    if record.len() <= i {
+        record.push(Vec::new());
+    }
+    record[i].clear();
+    record[i].extend_from_slice(token);
+}
+
+/// Reads the next NUL-terminated token of output (minus its NUL) into `buf`,
+/// returning false when there are no more.
+fn next_token<R: BufRead>(reader: &mut R, buf: &mut Vec<u8>) -> Result<bool, ParseError> {
+    buf.clear();
+    if reader.read_until(0, buf).map_err(ParseError::Read)? == 0 {
+        return Ok(false);
+    }
+    if buf.last() == Some(&0) {
+        buf.pop();
+    }
+    Ok(true)
+}
+
+/// Returns how many paths follow the first token of a delta.
+///
+/// Renames and copies have two (old, then new), and everything else has one,
+/// which is right after the status.
+/// `--numstat` records have their path in the first token, unless it's
+/// a rename or copy, where it's left empty and the two paths follow.
+///
+/// Everything past the change code gets checked when we parse the delta.
+fn paths_after(first: &[u8]) -> usize {
+    if is_numstat_line(first) {
+        match first.splitn(3, |&b| b == b'\t').nth(2) {
+            Some(p) if !p.is_empty() => 0,
+            _ => 2
+        }
+    }
+    else {
+        // --raw records have the change code after the modes and blobs.
+        let code = first.rsplit(|&b| b == b' ').next().unwrap_or(first);
+        match code.first() {
+            // Combined diffs (see is_combined_code()) only give the new path.
+            Some(b'R') | Some(b'C') if !is_combined_code(code) => 2,
+            _ => 1
+        }
+    }
+}
+
+/// Adds a `--name-status`, `--raw`, or `--numstat` record
+/// (its first token, then any paths) to the given commit.
+fn add_delta(options: &LogOptions, commit: &mut ParsedCommit,
+             lines: &mut DeltaLines, record: &[Vec<u8>]) -> Result<(), ParseError> {
+    let bad_delta = |message| ParseError::BadDelta{ commit: commit.id, message };
+
+    if is_numstat_line(&record[0]) {
+        let stat = match parse_numstat(&record[0]) {
+            Ok(stat) => Some(stat),
+            // Leave the delta without its stats.
+            Err(_) if options.lenient_deltas => None,
+            Err(e) => return Err(bad_delta(e))
+        };
 
-                    current_commit.deltas.push(parse_delta(&line));
+        if options.merges == MergeDiffs::Combined && commit.parents.len() > 1 {
+            // Renames and copies have the (new) path in the last token.
+            let path = match record.len() {
+                1 => record[0].splitn(3, |&b| b == b'\t').nth(2).unwrap_or(&[]).to_vec(),
+                _ => record[record.len() - 1].clone()
+            };
+            lines.merge_stats.push((path, stat));
+            return Ok(());
+        }
+
+        let slot = match lines.deltas.get(lines.stats_seen) {
+            Some(&s) => s,
+            None => return Err(bad_delta("Got more --numstat records than deltas".to_string()))
+        };
+        lines.stats_seen += 1;
+
+        if let (Some(i), Some(stat)) = (slot, stat) {
+            commit.deltas[i].numstat = Some(stat);
+        }
+    }
+    else {
+        match parse_delta(record, options.lossy_paths) {
+            Ok(mut delta) => {
+                if options.raw_lines {
+                    delta.raw = Some(record_string(record));
+                }
+                if !lines.merge_stats.is_empty() {
+                    delta.numstat = lines.merge_stats.iter()
+                        .find(|s| &s.0[..] == path_bytes(&delta.path))
+                        .and_then(|s| s.1);
                 }
+                lines.deltas.push(Some(commit.deltas.len()));
+                commit.deltas.push(delta);
+            }
+            Err(DeltaError::Malformed(e)) if !options.lenient_deltas => return Err(bad_delta(e)),
+            // A path we can't store wouldn't match anything anyway.
+            Err(_) => {
+                lines.deltas.push(None);
+                commit.skipped_deltas.push(record_string(record));
             }
         }
-        state = next_state;
     }
+    Ok(())
+}
 
-    // Grab the last commit.
-    commit_sink(current_commit, sink);
+/// Joins a delta's tokens with tabs, like Git prints them without `-z`
+fn record_string(record: &[Vec<u8>]) -> String {
+    let joined: Vec<u8> = record.join(&b'\t');
+    String::from_utf8_lossy(&joined).into_owned()
 }
 
-/// Sends a commit when the state machine is done parsing it.
+/// Sends a commit when the state machine is done parsing it,
+/// returning false if the other end stopped listening.
+///
+/// That's not an error - the consumer could have stopped early
+/// (see `history::GatherOptions::stop_when()`).
 #[inline]
-fn commit_sink(c: ParsedCommit, sink: &SyncSender<ParsedCommit>) {
-    sink.send(c).expect("The other end stopped listening for commits.");
+fn commit_sink(c: ParsedCommit, sink: &SyncSender<ParsedCommit>) -> bool {
+    sink.send(c).is_ok()
 }
 
-/// Parses a delta line generated by `git log --name-status`
-fn parse_delta(s: &str) -> FileDelta {
-    let tokens : Vec<&str> = s.split('\t').collect();
+/// Parses a delta generated by `git log -z --name-status` or `--raw`:
+/// its status, then one or two paths (see `paths_after()`)
+///
+/// `--raw` statuses are the same as `--name-status` ones, but with the file
+/// modes and blob IDs in front of the change code, e.g.
+/// `:100644 100644 6d3e6a7 33ae104 M`.
+/// Merges diffed with `--cc` have a colon, a mode, a blob, and a letter of the
+/// change code for each parent, e.g. `::100644 100644 100644 a1 b2 c3 MM`.
+///
+/// Paths that aren't valid UTF-8 (without `bytes-paths`) are
+/// a `DeltaError::NotUtf8` unless `lossy` is set.
+fn parse_delta(record: &[Vec<u8>], lossy: bool) -> Result<FileDelta, DeltaError> {
+    let s = || record_string(record); // For error messages
 
-    assert!(tokens.len() > 1, "Expected at least one token");
-    let c = parse_change_code(tokens[0]);
-    let previous : String;
-    let current : String;
+    let mut old_blob = None;
+    let mut new_blob = None;
+    let mut old_mode = None;
+    let mut new_mode = None;
+    let status = str::from_utf8(&record[0])
+        .map_err(|_| format!("Expected an ASCII change code in {:?}", s()))?;
+    let code = if status.starts_with(':') {
+            let parents = status.bytes().take_while(|&b| b == b':').count();
+            let meta : Vec<&str> = status.split(' ').collect();
+            // Modes and blobs for each parent and the result, then the code
+            if meta.len() != 2 * (parents + 1) + 1 {
+                return Err(format!("Expected {} --raw fields in {:?}",
+                                   2 * (parents + 1) + 1, s()).into());
+            }
+            // For merges, the "before" side is the first parent.
+            old_blob = parse_blob(meta[parents + 1]);
+            new_blob = parse_blob(meta[2 * parents + 1]);
+            old_mode = parse_mode(&meta[0][parents..]);
+            new_mode = parse_mode(meta[parents]);
+            meta[2 * parents + 2]
+        }
+        else {
+            status
+        };
+    let c = if is_combined_code(code.as_bytes()) {
+            parse_combined_code(code)?
+        }
+        else {
+            parse_change_code(code)?
+        };
+    let previous : GitPath;
+    let current : GitPath;
 
     match c {
         Change::Renamed { .. } |
         Change::Copied { .. }=> {
-            assert_eq!(tokens.len(), 3, "Expected three tokens from string {:?}", s);
-            current = tokens[2].to_string();
-            previous = tokens[1].to_string();
+            if record.len() != 3 {
+                return Err(format!("Expected two paths in {:?}", s()).into());
+            }
+            current = delta_path(&record[2], lossy)?;
+            previous = delta_path(&record[1], lossy)?;
         }
 
         _ => {
-            assert_eq!(tokens.len(), 2, "Expected two tokens from string {:?}", s);
-            current = tokens[1].to_string();
-            previous = String::new();
+            if record.len() != 2 {
+                return Err(format!("Expected one path in {:?}", s()).into());
+            }
+            current = delta_path(&record[1], lossy)?;
+            previous = GitPath::new();
         }
     };
 
-    FileDelta{ change: c, path: current, from: previous }
+    Ok(FileDelta{ change: c, path: current, from: previous,
+                  numstat: None, old_blob, new_blob, old_mode, new_mode,
+                  raw: None, parent: None })
+}
+
+/// Why `parse_delta()` gave up on a delta
+#[derive(Debug)]
+enum DeltaError {
+    /// Git printed something we don't understand.
+    Malformed(String),
+    /// A path isn't valid UTF-8 (see `LogOptions::lossy_paths()`).
+    NotUtf8,
+}
+
+impl From<String> for DeltaError {
+    fn from(message: String) -> DeltaError { DeltaError::Malformed(message) }
+}
+
+/// Converts a path from a delta, mangling it into UTF-8 if `lossy`
+/// is set and complaining otherwise.
+fn delta_path(bytes: &[u8], lossy: bool) -> Result<GitPath, DeltaError> {
+    convert_path(bytes.to_vec(), lossy).ok_or(DeltaError::NotUtf8)
+}
+
+/// Converts a path from Git, mangling it into UTF-8 if `lossy` is set,
+/// or returns `None` if it isn't valid UTF-8 (and we need it to be)
+fn convert_path(bytes: Vec<u8>, lossy: bool) -> Option<GitPath> {
+    match try_path_from_bytes(bytes) {
+        Ok(p) => Some(p),
+        Err(b) if lossy => Some(path_from_bytes(b)),
+        Err(_) => None
+    }
+}
+
+/// Parses a blob ID from a `--raw` line.
+///
+/// Git uses all zeroes for a side of the change where the file doesn't exist.
+/// We also get nothing from abbreviated IDs, but we always ask for full ones.
+fn parse_blob(s: &str) -> Option<SHA1> {
+    match SHA1::parse(s) {
+        Ok(id) if id != SHA1::default() => Some(id),
+        _ => None
+    }
+}
+
+/// Parses a file mode from `--raw` output, where all zeroes means
+/// there's no file on that side of the change
+fn parse_mode(s: &str) -> Option<u32> {
+    match u32::from_str_radix(s, 8) {
+        Ok(m) if m != 0 => Some(m),
+        _ => None
+    }
+}
+
+/// Change codes are letters and `--raw` lines start with a colon,
+/// so a line starting with a digit or a dash must be from `--numstat`.
+fn is_numstat_line(s: &[u8]) -> bool {
+    match s.first() {
+        Some(&c) => c == b'-' || c.is_ascii_digit(),
+        None => false
+    }
+}
+
+/// Parses the counts from a `git log --numstat` record, e.g. `12\t3\tfoo.txt`
+/// (or `12\t3\t` for a rename or copy, with the paths in their own tokens)
+///
+/// We only care about the counts - the record's position tells us which delta
+/// it goes with.
+fn parse_numstat(s: &[u8]) -> Result<NumStat, String> {
+    let bad = || format!("Couldn't parse --numstat line {:?}", String::from_utf8_lossy(s));
+
+    let tokens : Vec<&[u8]> = s.splitn(3, |&b| b == b'\t').collect();
+    if tokens.len() != 3 {
+        return Err(bad());
+    }
+
+    match (tokens[0], tokens[1]) {
+        (b"-", b"-") => Ok(NumStat::Binary),
+        (added, removed) => {
+            let count = |c| str::from_utf8(c).ok().and_then(|c| c.parse().ok());
+            match (count(added), count(removed)) {
+                (Some(added), Some(removed)) => Ok(NumStat::Text{ added, removed }),
+                _ => Err(bad())
+            }
+        }
+    }
+}
+
+/// Returns true if the change code is from a `--cc` diff of a merge,
+/// which has a letter for each parent (e.g. `MM`) instead of one letter
+/// and maybe a similarity index
+fn is_combined_code(code: &[u8]) -> bool {
+    code.len() > 1 && code.iter().all(u8::is_ascii_alphabetic)
+}
+
+/// Parses a combined change code (see `is_combined_code()`).
+///
+/// The file was only added or deleted if it was relative to every parent.
+/// Otherwise it existed on some side, so we call it a modification
+/// (even if it was renamed - combined diffs only give us the new path).
+fn parse_combined_code(c: &str) -> Result<Change, String> {
+    if !c.chars().all(|l| "ACDMRT".contains(l)) {
+        return Err(format!("Unknown delta code: {:?}", c));
+    }
+    if c.chars().all(|l| l == 'A') {
+        Ok(Change::Added)
+    }
+    else if c.chars().all(|l| l == 'D') {
+        Ok(Change::Deleted)
+    }
+    else {
+        Ok(Change::Modified)
+    }
 }
 
 /// Parses the change code generated by `git log --name-status`
-fn parse_change_code(c: &str) -> Change {
-    assert!(!c.is_empty());
-    let ret = match c.chars().nth(0).unwrap() {
-        'A' => Change::Added,
-        'D' => Change::Deleted,
-        'M' |
-        'T' => Change::Modified, // Let's consider a type change a modification.
-        // Renames and copies are suffixed with a percent changed, e.g. "R87"
-        'R' => Change::Renamed{ percent_changed: c[1..].parse().unwrap() },
-        'C' => Change::Copied{ percent_changed: c[1..].parse().unwrap() },
-        _ => panic!("Unknown delta code: {}", c)
+fn parse_change_code(c: &str) -> Result<Change, String> {
+    // Renames and copies are suffixed with a similarity index, e.g. "R87"
+    let similarity = || match c.get(1..).map(str::parse) {
+        Some(Ok(s)) if s <= 100 => Ok(s),
+        _ => Err(format!("Bad similarity index in delta code {:?}", c))
     };
 
-    // Sanity check percent_changed values for renames and copies
-    match ret {
-        Change::Renamed{ percent_changed: r}  => { assert!(r <= 100); },
-        Change::Copied{ percent_changed: c} => { assert!(c <= 100); },
-        _ => { }
-    };
+    match c.chars().next() {
+        Some('A') => Ok(Change::Added),
+        Some('D') => Ok(Change::Deleted),
+        Some('M') => Ok(Change::Modified),
+        Some('T') => Ok(Change::TypeChanged),
+        // Unmerged paths only show up in the working tree (mid-conflict),
+        // and broken pairs only with -B, but either way the file is still
+        // there with different contents.
+        Some('U') |
+        Some('B') => Ok(Change::Modified),
+        Some('X') => Err(format!("Git doesn't know what kind of change {:?} is \
+                                  (which is probably a Git bug)", c)),
+        Some('R') => Ok(Change::Renamed{ similarity: similarity()? }),
+        Some('C') => Ok(Change::Copied{ similarity: similarity()? }),
+        _ => Err(format!("Unknown delta code: {:?}", c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::*;
+
+    #[test]
+    fn parses_renames_copies_and_deletions() {
+        let commits = Log::new()
+            .commit(3, &[2], &["R100", "a", "b", "C075", "b", "c", "D", "d", "M", "e"])
+            .commit(2, &[1], &["A", "a", "M", "e"])
+            .commit(1, &[], &["A", "d", "A", "e"])
+            .parse(&LogOptions::new()).unwrap();
+
+        assert_eq!(commits.len(), 3);
+        let ids: Vec<_> = commits.iter().map(|c| c.id).collect();
+        assert_eq!(ids, [sha(3), sha(2), sha(1)]);
+        assert_eq!(commits[0].parents, [sha(2)]);
+        assert!(commits[2].parents.is_empty());
+        assert_eq!(commits[0].when, from_unix_seconds(when(3)));
+
+        let changes: Vec<_> = commits[0].deltas.iter()
+            .map(|d| (d.change, d.from.clone(), d.path.clone()))
+            .collect();
+        assert_eq!(changes, [(Change::Renamed{ similarity: 100 }, path("a"), path("b")),
+                             (Change::Copied{ similarity: 75 }, path("b"), path("c")),
+                             (Change::Deleted, path(""), path("d")),
+                             (Change::Modified, path(""), path("e"))]);
+        assert!(commits.iter().all(|c| c.skipped_deltas.is_empty()));
+    }
+
+    #[test]
+    fn parses_commits_without_deltas() {
+        let commits = Log::new()
+            .commit(3, &[2, 1], NO_DELTAS)
+            .commit(2, &[1], NO_DELTAS)
+            .commit(1, &[], &["A", "a"])
+            .parse(&LogOptions::new()).unwrap();
+
+        assert_eq!(commits.len(), 3);
+        assert_eq!(commits[0].parents, [sha(2), sha(1)]);
+        assert!(commits[0].deltas.is_empty());
+        assert!(commits[1].deltas.is_empty());
+        assert_eq!(commits[2].deltas[0].path, path("a"));
+    }
+
+    #[test]
+    fn parses_combined_merges() {
+        let commits = Log::new()
+            .combined(4, &[3, 2], &["MM", "f", "AA", "g"])
+            .commit(3, &[1], &["M", "f"])
+            .commit(2, &[1], &["M", "f"])
+            .commit(1, &[], &["A", "f"])
+            .parse(LogOptions::new().merges(MergeDiffs::Combined)).unwrap();
+
+        assert_eq!(commits.len(), 4);
+        assert_eq!(commits[0].parents, [sha(3), sha(2)]);
+        let changes: Vec<_> = commits[0].deltas.iter()
+            .map(|d| (d.change, d.path.clone()))
+            .collect();
+        assert_eq!(changes, [(Change::Modified, path("f")), (Change::Added, path("g"))]);
+        assert_eq!(commits[1].deltas.len(), 1);
+    }
+
+    #[test]
+    fn parses_hash_only_headers() {
+        let hash = |n| sha(n).to_string();
+        let commits = Log::new()
+            .record(&[&hash(2), &hash(1)], &["M", "a"])
+            .record(&[&hash(1), ""], &["A", "a"])
+            .parse(LogOptions::new().header(HeaderFormat::Hash)).unwrap();
+
+        assert_eq!(commits.len(), 2);
+        assert_eq!(commits[0].parents, [sha(1)]);
+        assert!(commits[1].parents.is_empty());
+        assert!(commits.iter().all(|c| c.when == UNIX_EPOCH));
+        assert_eq!(commits[1].deltas[0].change, Change::Added);
+    }
+
+    /// Returns the `BadHeader` we get parsing `log` with a hash-only header.
+    fn hash_only_mismatch(log: &[u8]) -> (String, String) {
+        match parse(log, LogOptions::new().header(HeaderFormat::Hash)) {
+            Err(ParseError::BadHeader{ line, format, .. }) => (line, format),
+            other => panic!("Expected a BadHeader, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn rejects_hash_only_tformat_logs() {
+        // git log -z --name-status --format=%H%x00
+        let log = format!("{}\0\0\nM\0f\0{}\0\0\nA\0f\0", sha(2), sha(1));
+        let (line, format) = hash_only_mismatch(log.as_bytes());
+        assert_eq!(line, sha(1).to_string());
+        assert_eq!(format, "%H%x00%P%x00");
+    }
+
+    #[test]
+    fn rejects_logs_without_nuls() {
+        // git log --name-status --format=%H
+        let log = format!("{}\n\nM\tf\n\n{}\n\nA\tf\n", sha(2), sha(1));
+        let (line, _) = hash_only_mismatch(log.as_bytes());
+        assert_eq!(line, sha(2).to_string());
+    }
+
+    #[test]
+    fn rejects_timestamps_when_expecting_hash_only() {
+        let log = Log::new().commit(1, &[], &["A", "f"]).bytes().to_vec();
+        let (line, _) = hash_only_mismatch(&log);
+        assert_eq!(line, format!("{} +0000", when(1)));
+    }
+
+    #[test]
+    fn hash_like_paths_dont_start_commits() {
+        // Content-addressed caches and the like
+        let blob = "0123456789abcdef0123456789abcdef01234567";
+        let moved = "89abcdef0123456789abcdef0123456789abcdef";
+        let commits = Log::new()
+            .commit(3, &[2], &["M", blob, "R100", blob, moved])
+            .commit(2, &[1], &["M", "a", "A", blob])
+            .commit(1, &[], &["A", "a"])
+            .parse(&LogOptions::new()).unwrap();
+
+        assert_eq!(commits.len(), 3);
+        let paths: Vec<_> = commits[0].deltas.iter().map(|d| d.path.clone()).collect();
+        assert_eq!(paths, [path(blob), path(moved)]);
+        assert_eq!(commits[0].deltas[1].from, path(blob));
+        assert_eq!(commits[1].deltas[1].path, path(blob));
+        assert_eq!(commits[2].id, sha(1));
+    }
+
+    #[test]
+    fn takes_special_paths_verbatim() {
+        // Without -z, Git would quote and escape all of these.
+        let odd = ["tab\there", "\"quoted\"", "new\nline", "back\\slash", "caf\u{e9}.rs",
+                   " spaces "];
+        let mut deltas = Vec::new();
+        for p in &odd {
+            deltas.push("A");
+            deltas.push(p);
+        }
+        deltas.extend(&["R100", odd[0], "\"moved\""]);
+        let commits = Log::new().commit(1, &[], &deltas).parse(&LogOptions::new()).unwrap();
+
+        let paths: Vec<_> = commits[0].deltas.iter().map(|d| d.path.clone()).collect();
+        let mut expected: Vec<_> = odd.iter().map(|p| path(p)).collect();
+        expected.push(path("\"moved\""));
+        assert_eq!(paths, expected);
+        assert_eq!(commits[0].deltas[odd.len()].from, path("tab\there"));
+    }
+
+    /// A log with a file whose name isn't valid UTF-8
+    fn non_utf8_log() -> Log {
+        let mut log = Log::new();
+        log.commit(2, &[1], &[&b"M"[..], b"caf\xe9", b"M", b"a"])
+            .commit(1, &[], &[&b"A"[..], b"caf\xe9", b"A", b"a"]);
+        log
+    }
+
+    #[cfg(not(feature = "bytes-paths"))]
+    #[test]
+    fn skips_non_utf8_paths() {
+        let commits = non_utf8_log().parse(&LogOptions::new()).unwrap();
+        assert_eq!(commits.len(), 2);
+        for c in &commits {
+            assert_eq!(c.deltas.len(), 1);
+            assert_eq!(c.deltas[0].path, path("a"));
+            assert_eq!(c.skipped_deltas.len(), 1);
+            assert!(c.skipped_deltas[0].starts_with(&['A', 'M'][..]));
+        }
+    }
+
+    #[cfg(not(feature = "bytes-paths"))]
+    #[test]
+    fn mangles_non_utf8_paths_if_asked() {
+        let commits = non_utf8_log().parse(LogOptions::new().lossy_paths(true)).unwrap();
+        assert_eq!(commits[0].deltas[0].path, path("caf\u{fffd}"));
+        assert!(commits[0].skipped_deltas.is_empty());
+    }
+
+    #[cfg(feature = "bytes-paths")]
+    #[test]
+    fn keeps_non_utf8_paths_as_bytes() {
+        let commits = non_utf8_log().parse(&LogOptions::new()).unwrap();
+        assert_eq!(commits[0].deltas[0].path, b"caf\xe9");
+        assert!(commits[0].skipped_deltas.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tracked_files_handles_non_utf8_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let repo = Repo::new("tracked-non-utf8");
+        repo.write("a", "a");
+        repo.write(OsStr::from_bytes(b"caf\xe9"), "caf");
+        repo.git(&["add", "."]);
+        repo.git(&["commit", "-qm", "Add files"]);
+
+        let mut options = LogOptions::new();
+        options.repo(Some(repo.path.clone()));
+        let mut expected: PathSet = vec![path("a")].into_iter().collect();
+        if cfg!(feature = "bytes-paths") {
+            expected.insert(path_from_bytes(b"caf\xe9".to_vec()));
+        }
+        assert_eq!(tracked_files(&options).unwrap(), expected);
+
+        if !cfg!(feature = "bytes-paths") {
+            expected.insert(path("caf\u{fffd}"));
+        }
+        assert_eq!(tracked_files(options.lossy_paths(true)).unwrap(), expected);
+    }
+
+    #[test]
+    fn limits_the_commit_count() {
+        let limits = |options: &LogOptions| -> Vec<String> {
+            options.command_line().iter()
+                .map(|a| a.to_string_lossy().into_owned())
+                .filter(|a| a.starts_with("--max-count"))
+                .collect()
+        };
+        let mut options = LogOptions::new();
+        assert!(limits(&options).is_empty());
+        assert_eq!(limits(options.max_count(Some(25))), ["--max-count=25"]);
+        assert!(limits(options.max_count(None)).is_empty());
+    }
+
+    #[test]
+    fn sandboxes_the_config() {
+        let settings = |options: &LogOptions| -> Vec<String> {
+            let argv = options.command_line();
+            argv.windows(2)
+                .filter(|pair| pair[0] == "-c")
+                .map(|pair| pair[1].to_string_lossy().into_owned())
+                .collect()
+        };
+        let mut options = LogOptions::new();
+        assert!(settings(&options).is_empty());
+        assert!(options.environment().is_empty());
+
+        options.sandbox_config(true);
+        assert_eq!(settings(&options), ["diff.renames=copies", "color.ui=never",
+                                        "log.showSignature=false", "core.quotePath=false"]);
+        let null = NULL_DEVICE.to_string();
+        assert_eq!(options.environment(),
+                   [("GIT_CONFIG_GLOBAL".to_string(), null.clone()),
+                    ("GIT_CONFIG_SYSTEM".to_string(), null)]);
+    }
+
+    #[test]
+    fn parses_numstat() {
+        let mut options = LogOptions::new();
+        options.numstat(true);
+        let commits = Log::new()
+            .commit(2, &[1], &[&raw(10, 10, "R100") as &str, "old.txt", "new.txt",
+                               &raw(20, 21, "M"), "a.txt", &raw(30, 31, "M"), "img.png",
+                               "0\t0\t", "old.txt", "new.txt", "3\t2\ta.txt", "-\t-\timg.png"])
+            .commit(1, &[], &[&raw(0, 10, "A") as &str, "old.txt", &raw(0, 20, "A"), "a.txt",
+                             &raw(0, 30, "A"), "img.png",
+                             "1\t0\told.txt", "4\t0\ta.txt", "-\t-\timg.png"])
+            .parse(&options).unwrap();
+
+        let deltas = &commits[0].deltas;
+        let stats: Vec<_> = deltas.iter().map(|d| d.numstat).collect();
+        // A pure rename doesn't touch any lines.
+        assert_eq!(stats, [Some(NumStat::Text{ added: 0, removed: 0 }),
+                           Some(NumStat::Text{ added: 3, removed: 2 }),
+                           Some(NumStat::Binary)]);
+        let added: Vec<_> = deltas.iter().map(FileDelta::lines_added).collect();
+        let removed: Vec<_> = deltas.iter().map(FileDelta::lines_removed).collect();
+        assert_eq!(added, [Some(0), Some(3), None]);
+        assert_eq!(removed, [Some(0), Some(2), None]);
+        assert_eq!(deltas[0].from, path("old.txt"));
+        assert_eq!(commits[1].deltas[1].lines_added(), Some(4));
+        assert_eq!(commits[1].deltas[2].is_binary(), Some(true));
+    }
+
+    #[test]
+    fn validates_options() {
+        let bad = |options: &LogOptions| {
+            matches!(options.validate(), Err(ParseError::BadOptions(_)))
+        };
+        assert!(!bad(&LogOptions::new()));
+        assert!(bad(LogOptions::new().rename_threshold(Some(101))));
+        assert!(bad(LogOptions::new().copy_threshold(Some(200))));
+        assert!(bad(LogOptions::new().git_program(Some(String::new()))));
+        assert!(bad(LogOptions::new().revspec(Some(String::new()))));
+    }
 
-    ret
+    #[cfg(unix)]
+    #[test]
+    fn passes_non_utf8_repo_paths_to_git() {
+        use std::ffi::OsStr;
+        use std::fs;
+        use std::os::unix::ffi::OsStrExt;
+
+        let repo = Repo::new("non-utf8-git-dir");
+        repo.write("a", "a");
+        repo.git(&["add", "a"]);
+        repo.git(&["commit", "-qm", "Add a"]);
+        repo.git(&["clone", "-q", "--bare", ".", "bare.git"]);
+        let bare = repo.path.join(OsStr::from_bytes(b"bare-\xff.git"));
+        fs::rename(repo.path.join("bare.git"), &bare).unwrap();
+
+        let mut options = LogOptions::new();
+        options.git_dir(Some(bare.clone()));
+        let mut flag = OsString::from("--git-dir=");
+        flag.push(&bare);
+        assert!(options.command_line().contains(&flag));
+        assert!(options.working_tree_command_line().contains(&flag));
+        assert_eq!(tracked_files(&options).unwrap(), path_set(&["a"]));
+        let commits: Vec<_> = CommitIter::new(&options).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(commits.len(), 1);
+    }
+
+    /// Returns each delta's path and parent
+    fn parents_of(deltas: &[FileDelta]) -> Vec<(GitPath, Option<SHA1>)> {
+        deltas.iter().map(|d| (d.path.clone(), d.parent)).collect()
+    }
+
+    fn each_parent() -> LogOptions {
+        let mut options = LogOptions::new();
+        options.merges(MergeDiffs::EachParent);
+        options
+    }
+
+    #[test]
+    fn tags_clean_merges_with_each_parent() {
+        // f changed on one side and g on the other
+        let commits = Log::new()
+            .commit(4, &[3, 2], &["M", "g"])
+            .commit(4, &[3, 2], &["M", "f"])
+            .commit(3, &[1], &["M", "f"])
+            .commit(2, &[1], &["M", "g"])
+            .commit(1, &[], &["A", "f", "A", "g"])
+            .parse(&each_parent()).unwrap();
+
+        assert_eq!(commits.len(), 4);
+        assert_eq!(parents_of(&commits[0].deltas),
+                   [(path("g"), Some(sha(3))), (path("f"), Some(sha(2)))]);
+        assert_eq!(commits[1].id, sha(3));
+        assert_eq!(parents_of(&commits[1].deltas), [(path("f"), None)]);
+    }
+
+    #[test]
+    fn tags_conflicted_merges_with_the_first_parent_that_differs() {
+        // f was changed on both sides, then again to resolve the conflict.
+        let commits = Log::new()
+            .commit(4, &[3, 2], &["M", "f", "M", "g"])
+            .commit(4, &[3, 2], &["M", "f"])
+            .commit(3, &[1], &["M", "f"])
+            .commit(2, &[1], &["M", "f", "M", "g"])
+            .commit(1, &[], &["A", "f", "A", "g"])
+            .parse(&each_parent()).unwrap();
+
+        assert_eq!(commits.len(), 4);
+        assert_eq!(parents_of(&commits[0].deltas),
+                   [(path("f"), Some(sha(3))), (path("g"), Some(sha(3)))]);
+    }
+
+    #[test]
+    fn doesnt_guess_parents_when_diffs_are_missing() {
+        // Like git merge -s ours: the merge matches its first parent,
+        // so Git only prints the diff against the second.
+        let commits = Log::new()
+            .commit(4, &[3, 2], &["M", "f"])
+            .commit(3, &[1], NO_DELTAS)
+            .commit(2, &[1], &["M", "f"])
+            .commit(1, &[], &["A", "f"])
+            .parse(&each_parent()).unwrap();
+
+        assert_eq!(commits.len(), 4);
+        assert_eq!(parents_of(&commits[0].deltas), [(path("f"), None)]);
+    }
+
+    #[test]
+    fn folds_octopus_merges_into_one_delta_per_path() {
+        let commits = octopus_log().parse(&each_parent()).unwrap();
+        assert_eq!(commits.len(), 6);
+        assert_eq!(commits[0].parents, [sha(5), sha(4), sha(3), sha(2)]);
+        assert_eq!(parents_of(&commits[0].deltas),
+                   [(path("b"), Some(sha(5))), (path("c"), Some(sha(5))),
+                    (path("d"), Some(sha(5))), (path("a"), Some(sha(4)))]);
+    }
+
+    #[test]
+    fn parses_combined_octopus_merges() {
+        let commits = Log::new()
+            .combined(6, &[5, 4, 3, 2], &["MMMM", "e"])
+            .commit(5, &[1], NO_DELTAS)
+            .parse(LogOptions::new().merges(MergeDiffs::Combined)).unwrap();
+
+        assert_eq!(commits[0].parents.len(), 4);
+        assert_eq!(commits[0].deltas.len(), 1);
+        assert_eq!(commits[0].deltas[0].change, Change::Modified);
+        assert_eq!(commits[0].deltas[0].path, path("e"));
+    }
+
+    #[test]
+    fn parses_signature_statuses() {
+        let hash = |n: u32| sha(n).to_string();
+        let time = |n: u32| format!("{} +0000", when(n));
+        let mut log = Log::new();
+        log.record(&[&hash(3), &time(3), &hash(2), "G"], &["M", "a"])
+            .record(&[&hash(2), &time(2), &hash(1), "N"], &["M", "a"]);
+        let mut options = LogOptions::new();
+        options.signatures(true);
+
+        let commits = log.parse(&options).unwrap();
+        assert_eq!(commits[0].signature, Some(SignatureStatus::Good));
+        assert_eq!(commits[1].signature, Some(SignatureStatus::Unsigned));
+
+        log.record(&[&hash(1), &time(1), "", "Q"], &["A", "a"]);
+        match log.parse(&options) {
+            Err(ParseError::BadSignature{ commit, line }) => {
+                assert_eq!(commit, sha(1));
+                assert_eq!(line, "Q");
+            }
+            other => panic!("Expected BadSignature, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn skips_malformed_deltas_if_lenient() {
+        let mut log = Log::new();
+        log.commit(2, &[1], &["M", "good", "Q", "weird", "A", "also-good"])
+            .commit(1, &[], &["A", "good"]);
+
+        match log.parse(&LogOptions::new()) {
+            Err(ParseError::BadDelta{ commit, message }) => {
+                assert_eq!(commit, sha(2));
+                assert!(message.contains("\"Q\""), "{}", message);
+            }
+            other => panic!("Expected BadDelta, got {:?}", other)
+        }
+
+        let commits = log.parse(LogOptions::new().lenient_deltas(true)).unwrap();
+        let paths: Vec<_> = commits[0].deltas.iter().map(|d| d.path.clone()).collect();
+        assert_eq!(paths, [path("good"), path("also-good")]);
+        assert_eq!(commits[0].skipped_deltas, ["Q\tweird"]);
+        assert!(commits[1].skipped_deltas.is_empty());
+    }
+
+    /// A log whose second commit has a garbage timestamp
+    fn bad_timestamp_log() -> Log {
+        let mut log = Log::new();
+        log.commit(3, &[2], &["M", "a"])
+            .record(&[&sha(2).to_string(), "not a date", &sha(1).to_string()], &["M", "a"])
+            .commit(1, &[], &["A", "a"]);
+        log
+    }
+
+    #[test]
+    fn rejects_bad_timestamps() {
+        match bad_timestamp_log().parse(&LogOptions::new()) {
+            Err(ParseError::BadTimestamp{ commit, line }) => {
+                assert_eq!(commit, sha(2));
+                assert_eq!(line, "not a date");
+            }
+            other => panic!("Expected a BadTimestamp, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn dates_bad_timestamps_to_the_epoch_if_lenient() {
+        let commits = bad_timestamp_log().parse(LogOptions::new().lenient_timestamps(true))
+            .unwrap();
+        assert_eq!(commits.len(), 3);
+        assert_eq!(commits[1].when, UNIX_EPOCH);
+        assert_eq!(commits[1].bad_timestamp.as_deref(), Some("not a date"));
+        assert_eq!(commits[1].deltas.len(), 1);
+        assert_eq!(commits[0].bad_timestamp, None);
+    }
+
+    #[test]
+    fn parses_time_zones() {
+        let commits = Log::new()
+            .record(&[&sha(1).to_string(), "1500000000 -0830", ""], &["A", "a"])
+            .parse(&LogOptions::new()).unwrap();
+        assert_eq!(commits[0].when, from_unix_seconds(1_500_000_000));
+        assert_eq!(commits[0].utc_offset, -510);
+    }
+
+    /// A stand-in for Git that hangs instead of printing a log
+    #[cfg(unix)]
+    struct HangingGit {
+        path: PathBuf,
+    }
+
+    #[cfg(unix)]
+    impl HangingGit {
+        fn new() -> HangingGit {
+            let path = env::temp_dir().join(format!("git-historian-hang-{}", std::process::id()));
+            // Say there's a HEAD, then hang. exec so that killing us kills
+            // whatever holds the pipe.
+            let script = "#!/bin/sh\n\
+                          case \"$*\" in *rev-parse*) exit 0;; esac\n\
+                          exec sleep 30\n";
+            // Write it from another process: if one of the other tests' threads
+            // forked while we had it open for writing, running it would fail
+            // with ETXTBSY.
+            let status = Command::new("sh")
+                .arg("-c").arg("printf '%s' \"$1\" > \"$0\" && chmod 755 \"$0\"")
+                .arg(&path).arg(script)
+                .status().unwrap();
+            assert!(status.success());
+            HangingGit{ path }
+        }
+    }
+
+    #[cfg(unix)]
+    impl Drop for HangingGit {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kills_git_after_the_timeout() {
+        use std::time::Instant;
+
+        let git = HangingGit::new();
+        let mut options = LogOptions::new();
+        options.git_program(Some(git.path.to_str().unwrap().to_string()))
+            .timeout(Some(Duration::from_millis(200)));
+
+        let start = Instant::now();
+        let results: Vec<_> = CommitIter::new(&options).unwrap().collect();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(results.len(), 1);
+        match results[0] {
+            Err(ParseError::Timeout) => { }
+            ref other => panic!("Expected a Timeout, got {:?}", other)
+        }
+
+        let (tx, _rx) = mpsc::sync_channel(1);
+        match get_history_with(&options, &tx) {
+            Err(ParseError::Timeout) => { }
+            other => panic!("Expected a Timeout, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn walks_no_history_in_an_empty_repo() {
+        let repo = Repo::new("empty");
+        let mut options = LogOptions::new();
+        options.repo(Some(repo.path.clone()));
+        assert_eq!(CommitIter::new(&options).unwrap().count(), 0);
+        assert_eq!(CommitIter::new(options.working_tree(true)).unwrap().count(), 0);
+
+        repo.git(&["commit", "-q", "--allow-empty", "-m", "Nothing"]);
+        let commits: Vec<_> = CommitIter::new(options.working_tree(false)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(commits.len(), 1);
+        assert!(commits[0].parents.is_empty());
+        assert!(commits[0].deltas.is_empty());
+    }
+
+    #[test]
+    fn stops_when_the_receiver_hangs_up() {
+        let mut log = Log::new();
+        for n in (1..10).rev() {
+            log.commit(n, &[n - 1], &["M", "a"]);
+        }
+        let (tx, rx) = mpsc::sync_channel(1);
+        drop(rx);
+        assert!(parse_log(log.bytes(), &LogOptions::new(), &tx).is_ok());
+    }
 }
@@ -0,0 +1,194 @@
+//! Canned `git log` output for the tests, so they don't need a real repo
+//!
+//! The bytes are laid out just like the log `LogOptions::command_line()`
+//! asks Git for (see `parsing::parse_log()`).
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::mpsc::sync_channel;
+
+use parsing::{parse_log, LogOptions, ParsedCommit, ParseError, NULL_DEVICE};
+use types::*;
+
+/// For commits with no deltas
+pub const NO_DELTAS: &[&str] = &[];
+
+/// Returns a made-up (but valid) commit hash for the number `n`
+pub fn sha(n: u32) -> SHA1 {
+    SHA1::parse(&format!("{:040x}", n)).unwrap()
+}
+
+/// Returns the timestamp `Log::commit()` gives commit `n`,
+/// so that higher numbers are newer
+pub fn when(n: u32) -> i64 {
+    1_500_000_000 + 100 * n as i64
+}
+
+/// Returns `path` as a `GitPath`, whichever kind that is
+pub fn path(p: &str) -> GitPath {
+    path_from_bytes(p.as_bytes().to_vec())
+}
+
+/// Returns a `--raw` status (the first token of a `--raw` delta)
+/// for a regular file going from blob `old` to blob `new`,
+/// where zero means there's no file on that side
+pub fn raw(old: u32, new: u32, code: &str) -> String {
+    let mode = |blob| if blob == 0 { "000000" } else { "100644" };
+    format!(":{} {} {} {} {}", mode(old), mode(new), sha(old), sha(new), code)
+}
+
+/// Returns a `PathSet` of the given paths
+pub fn path_set(paths: &[&str]) -> PathSet {
+    paths.iter().map(|p| path(p)).collect()
+}
+
+/// Builds up a log, newest commit first (like Git prints it)
+#[derive(Debug, Default)]
+pub struct Log {
+    bytes: Vec<u8>,
+}
+
+impl Log {
+    pub fn new() -> Log { Log::default() }
+
+    /// Adds commit `n` with the default header (hash, timestamp, parents)
+    /// and the given delta tokens, e.g. `["M", "foo.rs", "R100", "a", "b"]`
+    pub fn commit<D: AsRef<[u8]>>(&mut self, n: u32, parents: &[u32], deltas: &[D])
+        -> &mut Log {
+        let when = format!("{} +0000", when(n));
+        self.record(&[&sha(n).to_string(), &when, &parents_of(parents)], deltas)
+    }
+
+    /// Adds merge `n` like `--cc` prints it, with an empty token
+    /// instead of a newline before its deltas
+    pub fn combined<D: AsRef<[u8]>>(&mut self, n: u32, parents: &[u32], deltas: &[D])
+        -> &mut Log {
+        self.separate();
+        let when = format!("{} +0000", when(n));
+        for field in &[sha(n).to_string(), when, parents_of(parents)] {
+            self.token(field.as_bytes());
+        }
+        self.token(b"");
+        for delta in deltas {
+            self.token(delta.as_ref());
+        }
+        self
+    }
+
+    /// Adds a commit with whatever header `fields` (the hash first)
+    /// and delta tokens we're given
+    pub fn record<D: AsRef<[u8]>>(&mut self, fields: &[&str], deltas: &[D]) -> &mut Log {
+        self.separate();
+        for field in fields {
+            self.token(field.as_bytes());
+        }
+        for (i, delta) in deltas.iter().enumerate() {
+            if i == 0 {
+                self.bytes.push(b'\n');
+            }
+            self.token(delta.as_ref());
+        }
+        self
+    }
+
+    pub fn bytes(&self) -> &[u8] { &self.bytes }
+
+    /// Runs the log through `parse_log()`
+    pub fn parse(&self, options: &LogOptions) -> Result<Vec<ParsedCommit>, ParseError> {
+        parse(&self.bytes, options)
+    }
+
+    /// Git ends each commit but the last with an extra NUL.
+    fn separate(&mut self) {
+        if !self.bytes.is_empty() {
+            self.bytes.push(0);
+        }
+    }
+
+    fn token(&mut self, t: &[u8]) {
+        self.bytes.extend_from_slice(t);
+        self.bytes.push(0);
+    }
+}
+
+/// An octopus merge of four branches, each of which changed its own file,
+/// as `git log -m` prints it
+pub fn octopus_log() -> Log {
+    let mut log = Log::new();
+    log.commit(6, &[5, 4, 3, 2], &["M", "b", "M", "c", "M", "d"])
+        .commit(6, &[5, 4, 3, 2], &["M", "a", "M", "c", "M", "d"])
+        .commit(6, &[5, 4, 3, 2], &["M", "a", "M", "b", "M", "d"])
+        .commit(6, &[5, 4, 3, 2], &["M", "a", "M", "b", "M", "c"])
+        .commit(5, &[1], &["M", "a"])
+        .commit(4, &[1], &["M", "b"])
+        .commit(3, &[1], &["M", "c"])
+        .commit(2, &[1], &["M", "d"])
+        .commit(1, &[], &["A", "a", "A", "b", "A", "c", "A", "d"]);
+    log
+}
+
+/// Runs `log` through `parse_log()`, collecting the commits
+pub fn parse(log: &[u8], options: &LogOptions) -> Result<Vec<ParsedCommit>, ParseError> {
+    // There can't be more commits than bytes, so we never block.
+    let (tx, rx) = sync_channel(log.len() + 1);
+    parse_log(log, options, &tx)?;
+    drop(tx);
+    Ok(rx.iter().collect())
+}
+
+fn parents_of(parents: &[u32]) -> String {
+    parents.iter().map(|&p| sha(p).to_string()).collect::<Vec<_>>().join(" ")
+}
+
+/// A scratch Git repo for the few tests that need to run Git,
+/// deleted when dropped
+pub struct Repo {
+    pub path: PathBuf,
+}
+
+impl Repo {
+    /// Makes an empty repo with a name unique to the test
+    pub fn new(name: &str) -> Repo {
+        let path = env::temp_dir().join(format!("git-historian-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        let repo = Repo{ path };
+        repo.git(&["init", "-q"]);
+        repo
+    }
+
+    /// Runs Git in the repo, panicking if it fails
+    pub fn git(&self, args: &[&str]) {
+        let status = Command::new("git")
+            .arg("-C").arg(&self.path)
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .env("GIT_CONFIG_GLOBAL", NULL_DEVICE)
+            .env("GIT_CONFIG_SYSTEM", NULL_DEVICE)
+            .args(args)
+            .status().unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    /// Returns the commit `rev` (e.g. `HEAD`) names
+    pub fn rev_parse(&self, rev: &str) -> SHA1 {
+        let output = Command::new("git")
+            .arg("-C").arg(&self.path)
+            .args(["rev-parse", "--verify", rev])
+            .output().unwrap();
+        assert!(output.status.success(), "git rev-parse {} failed", rev);
+        SHA1::parse(String::from_utf8_lossy(&output.stdout).trim()).unwrap()
+    }
+
+    /// Writes `contents` to the file at `name` in the working tree
+    pub fn write<P: AsRef<Path>>(&self, name: P, contents: &str) {
+        fs::write(self.path.join(name), contents).unwrap();
+    }
+}
+
+impl Drop for Repo {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
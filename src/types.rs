@@ -1,25 +1,199 @@
 //! Types common to the entire crate.
 
+use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::error::Error;
+use std::ffi::OsStr;
 use std::fmt::{self, Display, Formatter};
-use std::rc::Rc;
+use std::io;
+use std::rc::{Rc, Weak};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use parsing::Identity;
+
+/// Converts a Unix timestamp (in seconds), like the ones Git prints,
+/// to a `SystemTime`
+pub fn from_unix_seconds(sec: i64) -> SystemTime {
+    if sec >= 0 {
+        UNIX_EPOCH + Duration::from_secs(sec as u64)
+    }
+    else {
+        UNIX_EPOCH - Duration::from_secs(sec.unsigned_abs())
+    }
+}
+
+/// Converts a `SystemTime` to a Unix timestamp (in seconds),
+/// rounding down to the second before it
+///
+/// Commit times are in whole seconds, so this is lossless for them.
+pub fn unix_seconds(t: SystemTime) -> i64 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => {
+            let before = e.duration();
+            let sec = -(before.as_secs() as i64);
+            if before.subsec_nanos() > 0 { sec - 1 } else { sec }
+        }
+    }
+}
+
+/// Serializes `SystemTime`s as Unix timestamps (see `unix_seconds()`)
+#[cfg(feature = "serde")]
+mod unix_time {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(when: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_i64(unix_seconds(*when))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SystemTime, D::Error> {
+        i64::deserialize(d).map(from_unix_seconds)
+    }
+}
+
+/// A path in the repository
+///
+/// Git paths are just bytes, so with the `bytes-paths` feature,
+/// they're stored as such. Otherwise they're `String`s, which are much more
+/// convenient, but can't hold paths that aren't valid UTF-8.
+#[cfg(not(feature = "bytes-paths"))]
+pub type GitPath = String;
+
+/// A path in the repository, as raw bytes (see the `bytes-paths` feature)
+#[cfg(feature = "bytes-paths")]
+pub type GitPath = Vec<u8>;
+
+/// The borrowed form of `GitPath`
+#[cfg(not(feature = "bytes-paths"))]
+pub type GitPathRef = str;
+
+/// The borrowed form of `GitPath`
+#[cfg(feature = "bytes-paths")]
+pub type GitPathRef = [u8];
+
+/// Converts a path from Git's output into a `GitPath`
+///
+/// Without the `bytes-paths` feature, anything that isn't UTF-8 gets replaced
+/// with U+FFFD. Use `try_path_from_bytes()` to find out if that would happen.
+#[cfg(not(feature = "bytes-paths"))]
+pub fn path_from_bytes(bytes: Vec<u8>) -> GitPath {
+    match String::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned()
+    }
+}
+
+/// Converts a path from Git's output into a `GitPath`
+#[cfg(feature = "bytes-paths")]
+pub fn path_from_bytes(bytes: Vec<u8>) -> GitPath { bytes }
+
+/// Converts a path from Git's output into a `GitPath`,
+/// handing the bytes back if they aren't valid UTF-8.
+#[cfg(not(feature = "bytes-paths"))]
+pub fn try_path_from_bytes(bytes: Vec<u8>) -> Result<GitPath, Vec<u8>> {
+    String::from_utf8(bytes).map_err(|e| e.into_bytes())
+}
+
+/// Converts a path from Git's output into a `GitPath`,
+/// which always works with `bytes-paths`.
+#[cfg(feature = "bytes-paths")]
+pub fn try_path_from_bytes(bytes: Vec<u8>) -> Result<GitPath, Vec<u8>> { Ok(bytes) }
+
+/// Returns the path's bytes
+#[cfg(not(feature = "bytes-paths"))]
+pub fn path_bytes(path: &GitPathRef) -> &[u8] { path.as_bytes() }
+
+/// Returns the path's bytes
+#[cfg(feature = "bytes-paths")]
+pub fn path_bytes(path: &GitPathRef) -> &[u8] { path }
+
+/// Returns the path as an `OsStr` for passing to other programs
+///
+/// This only fails with `bytes-paths` on platforms other than Unix,
+/// where an `OsStr` can't hold arbitrary bytes (see `utf8_path_to_os()`).
+#[cfg(not(feature = "bytes-paths"))]
+pub fn path_to_os(path: &GitPathRef) -> io::Result<&OsStr> { Ok(OsStr::new(path)) }
+
+/// Returns the path as an `OsStr` for passing to other programs
+///
+/// This only fails with `bytes-paths` on platforms other than Unix,
+/// where an `OsStr` can't hold arbitrary bytes (see `utf8_path_to_os()`).
+#[cfg(all(feature = "bytes-paths", unix))]
+pub fn path_to_os(path: &GitPathRef) -> io::Result<&OsStr> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(OsStr::from_bytes(path))
+}
+
+/// Returns the path as an `OsStr` for passing to other programs
+///
+/// This only fails with `bytes-paths` on platforms other than Unix,
+/// where an `OsStr` can't hold arbitrary bytes (see `utf8_path_to_os()`).
+#[cfg(all(feature = "bytes-paths", not(unix)))]
+pub fn path_to_os(path: &GitPathRef) -> io::Result<&OsStr> { utf8_path_to_os(path) }
+
+/// Passes UTF-8 paths through to an `OsStr`, and gives an `InvalidInput`
+/// error for the rest, since we'd be handing Git the wrong path otherwise
+#[cfg(all(feature = "bytes-paths", any(test, not(unix))))]
+fn utf8_path_to_os(path: &GitPathRef) -> io::Result<&OsStr> {
+    std::str::from_utf8(path).map(OsStr::new).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput,
+                       format!("{:?} isn't valid UTF-8, so it can't be passed to Git here",
+                               display_path(path)))
+    })
+}
+
+/// Returns the path as a string for display
+/// (replacing anything that isn't UTF-8, if we're using `bytes-paths`)
+#[cfg(not(feature = "bytes-paths"))]
+pub fn display_path(path: &GitPathRef) -> Cow<'_, str> { Cow::Borrowed(path) }
+
+/// Returns the path as a string for display
+/// (replacing anything that isn't UTF-8, if we're using `bytes-paths`)
+#[cfg(feature = "bytes-paths")]
+pub fn display_path(path: &GitPathRef) -> Cow<'_, str> { String::from_utf8_lossy(path) }
 
 /// A set of paths, used to track which files we care about
-pub type PathSet = HashSet<String>;
+///
+/// Entries ending in `/` (or `/**`) are directories,
+/// standing in for every file under them (see the `history` module).
+pub type PathSet = HashSet<GitPath>;
 
 /// A change to a file in Git (or at least the kinds we care about)
 ///
 /// Copies and renames have additional info:
 /// how much of the file remained the same.
-#[derive(Debug, Copy, Clone)]
+/// This is Git's similarity index (the `nn` in `Rnn`/`Cnn`),
+/// so 100 means the contents are identical and lower values mean
+/// more of the file changed.
+///
+/// With the `serde` feature, changes serialize as their kind,
+/// plus the similarity for renames and copies,
+/// e.g. `{ "kind": "Renamed", "similarity": 93 }`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(tag = "kind"))]
 pub enum Change {
     Added,
     Deleted,
     Modified,
-    Renamed{ percent_changed: u8},
-    Copied{ percent_changed: u8},
+    /// The file changed type, e.g., from a regular file to a symlink.
+    /// Its history carries on like any other modification.
+    TypeChanged,
+    Renamed{ similarity: u8},
+    Copied{ similarity: u8},
+}
+
+/// Line counts for a change, as reported by `git log --numstat`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NumStat {
+    Text{ added: u32, removed: u32 },
+    /// Git doesn't count lines for binary files (it prints `-` for both).
+    Binary,
 }
 
 /// A change made to a given file in a commit
@@ -29,15 +203,101 @@ pub struct FileDelta {
     pub change: Change,
 
     /// The current path of the file
-    pub path: String,
+    pub path: GitPath,
 
     /// The previous path of the file if the change is a rename or copy,
-    /// and an empty string otherwise
-    pub from: String,
+    /// and an empty path otherwise
+    pub from: GitPath,
+
+    /// Line counts for the change, if `--numstat` output was parsed
+    /// (see `parsing::LogOptions::numstat()`)
+    pub numstat: Option<NumStat>,
+
+    /// The blob ID of the file before the change, if `--raw` output was parsed
+    /// (see `parsing::LogOptions::blobs()`) and the file existed beforehand
+    pub old_blob: Option<SHA1>,
+
+    /// The blob ID of the file after the change, if `--raw` output was parsed
+    /// and the file still exists afterwards
+    pub new_blob: Option<SHA1>,
+
+    /// The file's mode (e.g. `0o100644`, or `0o100755` for an executable)
+    /// before the change, if `--raw` output was parsed
+    /// and the file existed beforehand
+    pub old_mode: Option<u32>,
+
+    /// The file's mode after the change, if `--raw` output was parsed
+    /// and the file still exists afterwards
+    pub new_mode: Option<u32>,
+
+    /// The `git log` output this delta was parsed from (with its fields
+    /// separated by tabs), if `parsing::LogOptions::keep_raw_lines()` was set
+    pub raw: Option<String>,
+
+    /// For merges diffed against each parent
+    /// (see `parsing::MergeDiffs::EachParent`), the parent this delta is
+    /// relative to, if we could tell
+    pub parent: Option<SHA1>,
+}
+
+impl FileDelta {
+    /// Returns true if the change is a rename that left the contents untouched
+    /// (i.e., Git reported `R100`).
+    pub fn is_pure_rename(&self) -> bool {
+        match self.change {
+            Change::Renamed{ similarity } => similarity == 100,
+            _ => false
+        }
+    }
+
+    /// Returns the blob ID of the file's contents as of this change,
+    /// or for deletions, the contents that were deleted
+    pub fn blob(&self) -> Option<SHA1> {
+        match self.change {
+            Change::Deleted => self.old_blob,
+            _ => self.new_blob
+        }
+    }
+
+    /// Returns true if `--raw` output says the file's mode changed,
+    /// e.g., it was made executable
+    pub fn mode_changed(&self) -> bool {
+        match (self.old_mode, self.new_mode) {
+            (Some(o), Some(n)) => o != n,
+            _ => false
+        }
+    }
+
+    /// Returns whether Git considers the file binary,
+    /// or `None` if we don't know because `--numstat` output wasn't parsed.
+    ///
+    /// Git prints `-` for both line counts of a binary file. A change to
+    /// a text file that doesn't touch any lines (e.g., a `chmod`)
+    /// gets zeroes instead, so it isn't mistaken for binary.
+    pub fn is_binary(&self) -> Option<bool> {
+        self.numstat.map(|n| n == NumStat::Binary)
+    }
+
+    /// Returns how many lines the change added,
+    /// or `None` for binary files or if `--numstat` output wasn't parsed
+    pub fn lines_added(&self) -> Option<u32> {
+        match self.numstat {
+            Some(NumStat::Text{ added, .. }) => Some(added),
+            _ => None
+        }
+    }
+
+    /// Like `lines_added()`, but for lines removed
+    pub fn lines_removed(&self) -> Option<u32> {
+        match self.numstat {
+            Some(NumStat::Text{ removed, .. }) => Some(removed),
+            _ => None
+        }
+    }
 }
 
 /// A 20-byte SHA1 hash, used for identifying objects in Git.
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
 pub struct SHA1 {
     bytes: [u8; 20]
 }
@@ -48,38 +308,127 @@ pub enum SHA1ParseError {
     InvalidHexadecimal
 }
 
-impl Error for SHA1ParseError {
-    fn description(&self) -> &str {
-        match *self {
-            SHA1ParseError::IncorrectLength => "String is not 40 characters long",
-            SHA1ParseError::InvalidHexadecimal => "String is not valid hexadecimal",
-        }
-    }
-}
+impl Error for SHA1ParseError { }
 
 impl Display for SHA1ParseError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        self.description().fmt(f)
+        match *self {
+            SHA1ParseError::IncorrectLength => "String (or slice) is the wrong length for a SHA1",
+            SHA1ParseError::InvalidHexadecimal => "String is not valid hexadecimal",
+        }.fmt(f)
     }
 }
 
 impl SHA1 {
+    /// All zeroes, which Git uses as a "no object" ID.
+    ///
+    /// We also use it as the ID of the pseudo-commit for uncommitted changes
+    /// (see `parsing::LogOptions::working_tree()`).
+    pub const NULL: SHA1 = SHA1{ bytes: [0; 20] };
+
     /// Parses a SHA1 from a 40 character hex string
     pub fn parse(s: &str) -> Result<SHA1, SHA1ParseError> {
         if s.len() != 40 { return Err(SHA1ParseError::IncorrectLength) }
 
+        // A multi-byte character could throw the length off,
+        // so go byte by byte instead of slicing the string.
         let mut ret = SHA1::default();
 
-        for i in 0..20 {
-            let char_index = i * 2;
-            ret.bytes[i] = match u8::from_str_radix(&s[char_index .. char_index + 2], 16) {
-                    Ok(b) => b,
+        for (i, pair) in s.as_bytes().chunks(2).enumerate() {
+            let nibble = |b: u8| char::from(b).to_digit(16).map(|n| n as u8);
+            ret.bytes[i] = match (nibble(pair[0]), nibble(pair[1])) {
+                    (Some(hi), Some(lo)) => hi << 4 | lo,
                     _ => { return Err(SHA1ParseError::InvalidHexadecimal); },
                 };
         }
 
         Ok(ret)
     }
+
+    /// Parses an abbreviated SHA1, like the ones from `git log --oneline`
+    ///
+    /// Like Git, we want at least 4 hex digits (and no more than 40).
+    pub fn parse_prefix(s: &str) -> Result<ShortSHA1, SHA1ParseError> {
+        if s.len() < 4 || s.len() > 40 { return Err(SHA1ParseError::IncorrectLength) }
+
+        let mut ret = ShortSHA1{ bytes: [0; 20], digits: s.len() };
+
+        for (i, c) in s.chars().enumerate() {
+            let nibble = match c.to_digit(16) {
+                    Some(n) => n as u8,
+                    None => { return Err(SHA1ParseError::InvalidHexadecimal); },
+                };
+            ret.bytes[i / 2] |= if i.is_multiple_of(2) { nibble << 4 } else { nibble };
+        }
+
+        Ok(ret)
+    }
+
+    /// Returns true if this hash starts with `prefix`
+    pub fn matches_prefix(&self, prefix: &ShortSHA1) -> bool {
+        let whole = prefix.digits / 2;
+        self.bytes[..whole] == prefix.bytes[..whole] &&
+        (prefix.digits.is_multiple_of(2) || self.bytes[whole] >> 4 == prefix.bytes[whole] >> 4)
+    }
+
+    /// Returns the hash's raw bytes
+    pub fn as_bytes(&self) -> &[u8; 20] { &self.bytes }
+}
+
+/// An abbreviated SHA1 (see `SHA1::parse_prefix()`)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ShortSHA1 {
+    /// The digits we have, padded out with zeroes
+    bytes: [u8; 20],
+    digits: usize,
+}
+
+impl ShortSHA1 {
+    /// Returns how many hex digits long it is
+    pub fn digits(&self) -> usize { self.digits }
+}
+
+impl Display for ShortSHA1 {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let full = SHA1{ bytes: self.bytes }.to_string();
+        full[..self.digits].fmt(f)
+    }
+}
+
+/// SHA1s serialize as hex strings, like they're displayed.
+#[cfg(feature = "serde")]
+impl Serialize for SHA1 {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for SHA1 {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<SHA1, D::Error> {
+        SHA1::parse(&String::deserialize(d)?).map_err(de::Error::custom)
+    }
+}
+
+impl FromStr for SHA1 {
+    type Err = SHA1ParseError;
+
+    fn from_str(s: &str) -> Result<SHA1, SHA1ParseError> { SHA1::parse(s) }
+}
+
+impl From<[u8; 20]> for SHA1 {
+    fn from(bytes: [u8; 20]) -> SHA1 { SHA1{ bytes } }
+}
+
+/// Takes the hash's raw bytes, e.g., from another Git library
+impl<'a> TryFrom<&'a [u8]> for SHA1 {
+    type Error = SHA1ParseError;
+
+    fn try_from(bytes: &'a [u8]) -> Result<SHA1, SHA1ParseError> {
+        <[u8; 20]>::try_from(bytes)
+            .map(SHA1::from)
+            .map_err(|_| SHA1ParseError::IncorrectLength)
+    }
 }
 
 impl Display for SHA1 {
@@ -94,10 +443,6 @@ impl Display for SHA1 {
     }
 }
 
-impl Default for SHA1 {
-    fn default() -> SHA1 { SHA1{bytes: [0; 20]} }
-}
-
 /// Expresses an edge between `HistoryNodes` in a `HistoryTree`
 pub type Link<T> = Rc<RefCell<T>>;
 
@@ -107,15 +452,264 @@ pub struct HistoryNode<T> {
     /// whatever info they want about the change.
     /// This is an `Option` for the sake of filtering---we can't omit the node
     /// entirely (or the processing that generated it), as we could screw up
-    /// the history graph. Instead, we make the contents `None` for all nodes
-    /// from a filtered-out commit.
+    /// the history graph. Instead, we make the contents `None` for each node
+    /// whose change the filter rejected.
     pub data: Option<Rc<T>>,
 
+    /// The commit that made this change
+    pub id: SHA1,
+
+    /// When that commit was made (see `parsing::ParsedCommit::when`)
+    pub when: SystemTime,
+
+    /// Who wrote that commit, if the log included it
+    /// (see `parsing::LogOptions::identities()`)
+    pub author: Option<Identity>,
+
+    /// The file's path as of this change
+    pub path: GitPath,
+
+    /// What kind of change this was
+    pub change: Change,
+
+    /// The file's blob ID as of this change (see `FileDelta::blob()`),
+    /// if it's known
+    pub blob: Option<SHA1>,
+
     /// What's the previous change?
     pub previous: Option<Link<HistoryNode<T>>>,
+
+    /// The changes whose `previous` is this one (see `next_changes()`).
+    /// These are weak so that the links don't make an `Rc` cycle.
+    pub(crate) next: Vec<Weak<RefCell<HistoryNode<T>>>>,
+
+    /// See `cochanged_paths()`
+    pub(crate) cochanged: Vec<GitPath>,
+}
+
+impl<T> HistoryNode<T> {
+    /// Returns an ID for the node that's the same every time the history is
+    /// gathered, unlike the node's address.
+    /// Handy for exporting graphs that can be diffed or cached.
+    ///
+    /// A commit changes a path at most once, so `<SHA1>:<path>` does the trick.
+    pub fn stable_id(&self) -> String {
+        format!("{}:{}", self.id, display_path(&self.path))
+    }
+
+    /// Returns the other paths changed in the same commit,
+    /// if `history::GatherOptions::record_cochanges()` was set
+    /// (and nothing otherwise)
+    pub fn cochanged_paths(&self) -> &[GitPath] {
+        &self.cochanged
+    }
+
+    /// Returns the changes that came right after this one,
+    /// i.e., whose `previous` is this node
+    ///
+    /// There's usually just one, but a file that was copied has one for
+    /// each copy, plus its own next change.
+    /// This is kept up to date by the `history` module, so nodes that you
+    /// link yourself by setting `previous` won't show up.
+    pub fn next_changes(&self) -> Vec<Link<HistoryNode<T>>> {
+        self.next.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    /// Returns the next change to this file (following renames,
+    /// but not copies), if there is one
+    pub fn next_change(&self) -> Option<Link<HistoryNode<T>>> {
+        self.next_changes().into_iter()
+            .find(|n| !matches!(n.borrow().change, Change::Copied{..}))
+    }
+
+    /// Returns how similar the file stayed if this change was a rename or copy
+    /// (see `Change`), or `None` for additions, deletions, and modifications
+    pub fn change_similarity(&self) -> Option<u8> {
+        match self.change {
+            Change::Renamed{ similarity } |
+            Change::Copied{ similarity } => Some(similarity),
+            _ => None
+        }
+    }
+}
+
+/// A change in a file, copied out of the tree (see `history::flatten()`)
+///
+/// This is `HistoryNode` without the links, for consumers who'd rather not
+/// deal with `Rc`s and `RefCell`s.
+/// With the `serde` feature, it's serializable if `T` is,
+/// with `when` as a Unix timestamp.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NodeData<T> {
+    /// See `HistoryNode::data`
+    pub data: Option<T>,
+    pub id: SHA1,
+    #[cfg_attr(feature = "serde", serde(with = "unix_time"))]
+    pub when: SystemTime,
+    pub author: Option<Identity>,
+    pub path: GitPath,
+    pub change: Change,
+    pub blob: Option<SHA1>,
+}
+
+impl<T: Clone> NodeData<T> {
+    /// Copies everything but the links out of `node`
+    pub fn from_node(node: &HistoryNode<T>) -> NodeData<T> {
+        NodeData {
+            data: node.data.as_ref().map(|d| (**d).clone()),
+            id: node.id,
+            when: node.when,
+            author: node.author.clone(),
+            path: node.path.clone(),
+            change: node.change,
+            blob: node.blob,
+        }
+    }
 }
 
 /// For each key in the map, the value is a branch of a tree
 /// (i.e. a linked list) of all changes.
 /// This extends past name changes
-pub type HistoryTree<T> = HashMap<String, Link<HistoryNode<T>>>;
+pub type HistoryTree<T> = HashMap<GitPath, Link<HistoryNode<T>>>;
+
+/// A single entry of a `HistoryTree`: a path and the head of its branch
+pub type Branch<T> = (GitPath, Link<HistoryNode<T>>);
+
+/// Identifies a node in a `HistoryArena` (it's the node's index in
+/// `HistoryArena::nodes`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub usize);
+
+/// A change in a file, linked to others in its `HistoryArena` by `NodeId`
+#[derive(Debug, Clone)]
+pub struct ArenaNode<T> {
+    /// Everything but the links
+    pub node: NodeData<T>,
+
+    /// See `HistoryNode::previous`
+    pub previous: Option<NodeId>,
+
+    /// See `HistoryNode::next_changes()`
+    pub next: Vec<NodeId>,
+
+    /// See `HistoryNode::cochanged_paths()`
+    pub cochanged: Vec<GitPath>,
+}
+
+/// A `HistoryTree` stored as one list of nodes that refer to each other by
+/// index (see `history::to_arena()`)
+///
+/// There isn't an `Rc` or a `RefCell` in sight, so unlike the tree,
+/// it's `Send` and `Sync` (if `T` is) - handy for rendering the results
+/// on a thread pool.
+#[derive(Debug, Clone)]
+pub struct HistoryArena<T> {
+    pub nodes: Vec<ArenaNode<T>>,
+
+    /// The head of each branch, as in the `HistoryTree`
+    pub heads: HashMap<GitPath, NodeId>,
+}
+
+impl<T> HistoryArena<T> {
+    pub fn node(&self, id: NodeId) -> &ArenaNode<T> { &self.nodes[id.0] }
+
+    /// Walks from `head` to the start of its history, like `history::BranchIter`
+    pub fn walk(&self, head: NodeId) -> ArenaIter<'_, T> {
+        ArenaIter{ arena: self, current: Some(head) }
+    }
+
+    /// Walks the branch for `path`, if there is one
+    pub fn branch(&self, path: &GitPathRef) -> Option<ArenaIter<'_, T>> {
+        self.heads.get(path).map(|&h| self.walk(h))
+    }
+}
+
+/// Walks a branch of a `HistoryArena` (see `HistoryArena::walk()`)
+pub struct ArenaIter<'a, T: 'a> {
+    arena: &'a HistoryArena<T>,
+    current: Option<NodeId>,
+}
+
+impl<'a, T> Iterator for ArenaIter<'a, T> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let id = self.current.take()?;
+        self.current = self.arena.node(id).previous;
+        Some(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn parses_hashes() {
+        let sha = SHA1::parse(HASH).unwrap();
+        assert_eq!(sha.to_string(), HASH);
+        assert_eq!(HASH.parse::<SHA1>().unwrap(), sha);
+        assert_eq!(SHA1::parse(&HASH.to_uppercase()).unwrap(), sha);
+        assert_eq!(SHA1::try_from(&sha.as_bytes()[..]).unwrap(), sha);
+
+        assert!(matches!(SHA1::parse(&HASH[1..]), Err(SHA1ParseError::IncorrectLength)));
+        assert!(matches!(SHA1::parse(&format!("{}0", HASH)),
+                         Err(SHA1ParseError::IncorrectLength)));
+        assert!(matches!(SHA1::parse(&HASH.replace('a', "g")),
+                         Err(SHA1ParseError::InvalidHexadecimal)));
+        // 40 bytes, but not 40 hex digits (or even 40 characters)
+        assert!(matches!(SHA1::parse(&format!("a{}b", "\u{e9}".repeat(19))),
+                         Err(SHA1ParseError::InvalidHexadecimal)));
+        assert!(matches!(SHA1::try_from(&[0u8; 19][..]), Err(SHA1ParseError::IncorrectLength)));
+    }
+
+    #[test]
+    fn parses_prefixes() {
+        let sha = SHA1::parse(HASH).unwrap();
+        for len in &[4, 7, 12, 39, 40] {
+            let prefix = SHA1::parse_prefix(&HASH[..*len]).unwrap();
+            assert_eq!(prefix.digits(), *len);
+            assert_eq!(prefix.to_string(), &HASH[..*len]);
+            assert!(sha.matches_prefix(&prefix), "{} should match", prefix);
+        }
+
+        assert!(matches!(SHA1::parse_prefix("012"), Err(SHA1ParseError::IncorrectLength)));
+        assert!(matches!(SHA1::parse_prefix(&format!("{}0", HASH)),
+                         Err(SHA1ParseError::IncorrectLength)));
+        assert!(matches!(SHA1::parse_prefix("01x3"), Err(SHA1ParseError::InvalidHexadecimal)));
+    }
+
+    #[test]
+    fn mismatches_prefixes() {
+        let sha = SHA1::parse(HASH).unwrap();
+        // Odd lengths only compare the high nibble of the last byte...
+        assert!(!sha.matches_prefix(&SHA1::parse_prefix("01235").unwrap()));
+        assert!(!sha.matches_prefix(&SHA1::parse_prefix("01224").unwrap()));
+        // ...and even ones compare whole bytes.
+        assert!(!sha.matches_prefix(&SHA1::parse_prefix("012346").unwrap()));
+        assert!(!sha.matches_prefix(&SHA1::parse_prefix("1234").unwrap()));
+        assert!(!SHA1::NULL.matches_prefix(&SHA1::parse_prefix("0001").unwrap()));
+        assert!(SHA1::NULL.matches_prefix(&SHA1::parse_prefix("00000").unwrap()));
+    }
+
+    #[cfg(not(feature = "bytes-paths"))]
+    #[test]
+    fn converts_non_utf8_paths_lossily() {
+        assert_eq!(path_from_bytes(b"caf\xc3\xa9".to_vec()), "caf\u{e9}");
+        assert_eq!(path_from_bytes(b"caf\xe9".to_vec()), "caf\u{fffd}");
+        assert_eq!(try_path_from_bytes(b"caf\xe9".to_vec()), Err(b"caf\xe9".to_vec()));
+    }
+
+    #[cfg(feature = "bytes-paths")]
+    #[test]
+    fn only_passes_utf8_paths_where_os_strings_need_it() {
+        assert_eq!(utf8_path_to_os(b"caf\xc3\xa9").unwrap(), OsStr::new("caf\u{e9}"));
+        let err = utf8_path_to_os(b"caf\xe9").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("caf\u{fffd}"), "{}", err);
+    }
+}